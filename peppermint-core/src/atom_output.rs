@@ -0,0 +1,54 @@
+use crate::Id;
+use livi::event::LV2AtomSequence;
+
+/// The largest atom body that is forwarded in full. Larger bodies are
+/// truncated.
+pub const MAX_ATOM_OUTPUT_SIZE: usize = 256;
+
+/// An event that a plugin instance wrote to its atom output.
+#[derive(Copy, Clone)]
+pub struct AtomOutputEvent {
+    pub instance: Id,
+    /// The frame within the block.
+    pub frame: i64,
+    pub type_urid: lv2_raw::LV2Urid,
+    /// The size of the atom body before truncation.
+    pub size: usize,
+    data: [u8; MAX_ATOM_OUTPUT_SIZE],
+}
+
+impl AtomOutputEvent {
+    /// The body of the atom. This is truncated to `MAX_ATOM_OUTPUT_SIZE`
+    /// bytes.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.size.min(MAX_ATOM_OUTPUT_SIZE)]
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.size > MAX_ATOM_OUTPUT_SIZE
+    }
+}
+
+/// Copy all events in `sequence` to `queue`. Events are dropped if the queue
+/// is full.
+pub fn forward(
+    instance: Id,
+    sequence: &LV2AtomSequence,
+    queue: &mut ringbuf::Producer<AtomOutputEvent>,
+) {
+    for event in sequence.iter() {
+        let mut data = [0; MAX_ATOM_OUTPUT_SIZE];
+        let size = event.data.len().min(MAX_ATOM_OUTPUT_SIZE);
+        data[..size].copy_from_slice(&event.data[..size]);
+        let output = AtomOutputEvent {
+            instance,
+            frame: event.event.time_in_frames,
+            type_urid: event.event.body.mytype,
+            size: event.data.len(),
+            data,
+        };
+        if queue.push(output).is_err() {
+            return;
+        }
+    }
+}
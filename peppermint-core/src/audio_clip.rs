@@ -0,0 +1,188 @@
+use crate::channels::FixedChannels;
+use crate::transport::TransportCycle;
+use crate::Id;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of frames in a `StreamBlock`.
+pub const STREAM_BLOCK_FRAMES: usize = 512;
+
+/// When the playhead jumps while playing, audio is requested from this many
+/// frames ahead of the playhead to give the streaming thread time to catch up.
+const PRELOAD_FRAMES: u64 = 4096;
+
+/// A chunk of audio that has been read from disk.
+#[derive(Copy, Clone)]
+pub struct StreamBlock {
+    /// The generation of the `StreamRequest` that the block was read for.
+    pub generation: u64,
+    /// The position of the first sample on the timeline.
+    pub frame: u64,
+    pub len: usize,
+    pub samples: [[f32; 2]; STREAM_BLOCK_FRAMES],
+}
+
+/// The position on the timeline that the audio thread needs audio from. The
+/// generation is incremented on every request so that the streaming thread
+/// can tell when it should seek.
+#[derive(Debug, Default)]
+pub struct StreamRequest {
+    generation: AtomicU64,
+    frame: AtomicU64,
+}
+
+impl StreamRequest {
+    /// Returns the generation and frame of the latest request.
+    pub fn get(&self) -> (u64, u64) {
+        let generation = self.generation.load(Ordering::Acquire);
+        (generation, self.frame.load(Ordering::Relaxed))
+    }
+
+    /// Request audio from `frame` and return the generation of the request.
+    fn set(&self, frame: u64) -> u64 {
+        self.frame.store(frame, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::AcqRel) + 1
+    }
+}
+
+/// The part of a file that is played and where it is placed. All values are
+/// in frames.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AudioClipRegion {
+    /// The position of the clip on the timeline.
+    pub start: u64,
+    /// The first frame of the file that is played.
+    pub offset: u64,
+    /// The number of frames that are played.
+    pub length: u64,
+}
+
+impl AudioClipRegion {
+    /// The position on the timeline just after the clip.
+    pub fn end(&self) -> u64 {
+        self.start + self.length
+    }
+}
+
+/// Plays back audio that is streamed from disk by another thread.
+pub struct AudioClip {
+    id: Id,
+    region: AudioClipRegion,
+    blocks: ringbuf::Consumer<StreamBlock>,
+    request: Arc<StreamRequest>,
+    generation: u64,
+    /// True if no block has been received since the last request.
+    request_pending: bool,
+    /// True if audio should be requested from the playhead on the next cycle.
+    needs_request: bool,
+}
+
+impl AudioClip {
+    /// Create a new clip. Blocks for the clip should be pushed to the
+    /// producer of `blocks` in response to `request`.
+    pub fn new(
+        id: Id,
+        region: AudioClipRegion,
+        blocks: ringbuf::Consumer<StreamBlock>,
+        request: Arc<StreamRequest>,
+    ) -> AudioClip {
+        AudioClip {
+            id,
+            region,
+            blocks,
+            request,
+            generation: 0,
+            request_pending: true,
+            needs_request: true,
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn set_region(&mut self, region: AudioClipRegion) {
+        self.region = region;
+        self.needs_request = true;
+    }
+
+    /// Mix the audio for the next `samples` frames into `output`. Frames that
+    /// have not been streamed in time are left silent.
+    pub fn mix_into(
+        &mut self,
+        output: &mut FixedChannels<2>,
+        transport: &TransportCycle,
+        samples: usize,
+    ) {
+        if self.needs_request || transport.jumped {
+            let preload = if transport.playing { PRELOAD_FRAMES } else { 0 };
+            self.request(transport.frame + preload);
+        }
+        if !transport.playing {
+            return;
+        }
+        let begin = transport.frame.max(self.region.start);
+        let end = (transport.frame + samples as u64).min(self.region.end());
+        let mut frame = begin;
+        while frame < end {
+            // Drop blocks from old requests or that are behind the playhead.
+            let generation = self.generation;
+            let mut stale = 0;
+            let mut received = false;
+            self.blocks.access(|a, b| {
+                for block in a.iter().chain(b.iter()) {
+                    received |= block.generation == generation;
+                    if block.generation == generation && block.frame + block.len as u64 > frame {
+                        break;
+                    }
+                    stale += 1;
+                }
+            });
+            self.blocks.discard(stale);
+            if received {
+                self.request_pending = false;
+            }
+            let mut next_frame = None;
+            self.blocks.access(|a, _| {
+                let block = match a.first() {
+                    Some(block) => block,
+                    None => return,
+                };
+                if block.frame > frame {
+                    next_frame = Some(block.frame.min(end));
+                    return;
+                }
+                let block_offset = (frame - block.frame) as usize;
+                let count = (block.len - block_offset).min((end - frame) as usize);
+                let output_offset = (frame - transport.frame) as usize;
+                for (channel_idx, channel) in output.iter_channels_mut().enumerate() {
+                    let source = &block.samples[block_offset..block_offset + count];
+                    for (out, sample) in channel[output_offset..output_offset + count]
+                        .iter_mut()
+                        .zip(source.iter())
+                    {
+                        *out += sample[channel_idx];
+                    }
+                }
+                next_frame = Some(frame + count as u64);
+            });
+            match next_frame {
+                Some(next_frame) => frame = next_frame,
+                None => {
+                    // The streaming thread fell behind. Skip ahead instead of
+                    // waiting for it to catch up.
+                    if !self.request_pending {
+                        self.request(frame + PRELOAD_FRAMES);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn request(&mut self, frame: u64) {
+        self.generation = self.request.set(frame);
+        self.request_pending = true;
+        self.needs_request = false;
+    }
+}
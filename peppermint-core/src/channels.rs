@@ -1,3 +1,4 @@
+use crate::smooth::Ramp;
 use std::fmt::Debug;
 
 pub struct FixedChannels<const N: usize> {
@@ -40,6 +41,41 @@ impl<const N: usize> FixedChannels<N> {
             *x += *y * gain;
         }
     }
+
+    /// Crossfade between `self` and `dry`. An `amount` of `1.0` leaves `self`
+    /// unchanged and an `amount` of `0.0` replaces it with `dry`.
+    pub fn blend(&mut self, dry: &FixedChannels<N>, amount: f32) {
+        debug_assert_eq!(self.buffer_size(), dry.buffer_size());
+        for (x, y) in self.audio.iter_mut().zip(dry.audio.iter()) {
+            *x = *x * amount + *y * (1.0 - amount);
+        }
+    }
+
+    /// Similar to `mix`, but the gain of each channel follows `ramp`.
+    pub fn mix_ramped(&mut self, other: &FixedChannels<N>, ramp: &Ramp<N>) {
+        debug_assert_eq!(self.buffer_size(), other.buffer_size());
+        for (channel, (dst, src)) in self
+            .iter_channels_mut()
+            .zip(other.iter_channels())
+            .enumerate()
+        {
+            let start = ramp.start[channel];
+            let end = ramp.end[channel];
+            let step = if ramp.samples > 0 {
+                (end - start) / ramp.samples as f32
+            } else {
+                0.0
+            };
+            for (idx, (x, y)) in dst.iter_mut().zip(src.iter()).enumerate() {
+                let gain = if idx < ramp.samples {
+                    start + step * idx as f32
+                } else {
+                    end
+                };
+                *x += *y * gain;
+            }
+        }
+    }
 }
 
 impl<const N: usize> Debug for FixedChannels<N> {
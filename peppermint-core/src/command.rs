@@ -1,12 +1,23 @@
 use crate::{
-    track::{Track, TrackProperty},
+    audio_clip::{AudioClip, AudioClipRegion},
+    midi::MidiInput,
+    midi_learn::MidiBinding,
+    pan::PanLaw,
+    recording::Recording,
+    sequencer::MidiClip,
+    track::{AudioInput, AuxSend, InstanceProperty, Track, TrackProperty},
+    transport::Tempo,
     Id,
 };
 
+// Boxing `Track` would require a deallocation on the audio thread.
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     CreateTrack(Track),
     DeleteTrack(Id),
     UpdateTrack(Id, TrackProperty, f32),
+    UpdateTrackMidiInput(Id, MidiInput),
+    UpdateTrackAudioInput(Id, AudioInput),
     PushPluginInstance {
         id: Id,
         track: Id,
@@ -15,4 +26,90 @@ pub enum Command {
     DeletePluginInstance {
         id: Id,
     },
+    SetPanLaw(PanLaw),
+    Play,
+    Stop,
+    Seek(u64),
+    SetTempo(Tempo),
+    CreateSend {
+        track: Id,
+        send: AuxSend,
+    },
+    UpdateSend {
+        id: Id,
+        level: f32,
+        pre_fader: bool,
+    },
+    DeleteSend {
+        id: Id,
+    },
+    MovePluginInstance {
+        id: Id,
+        track: Id,
+        index: usize,
+    },
+    UpdatePluginInstance {
+        id: Id,
+        port: livi::PortIndex,
+        value: f32,
+    },
+    UpdatePluginInstanceProperty {
+        id: Id,
+        property: InstanceProperty,
+        value: f32,
+    },
+    /// Add a clip to a track or replace the clip with the same id.
+    SetMidiClip {
+        track: Id,
+        clip: MidiClip,
+    },
+    DeleteMidiClip {
+        id: Id,
+    },
+    CreateAudioClip {
+        track: Id,
+        clip: AudioClip,
+    },
+    UpdateAudioClip {
+        id: Id,
+        region: AudioClipRegion,
+    },
+    DeleteAudioClip {
+        id: Id,
+    },
+    StartRecording(Recording),
+    StopRecording {
+        id: Id,
+    },
+    CreateMidiBinding(MidiBinding),
+    /// Remove all tracks and midi bindings and replace the master track.
+    ResetSession(Track),
+    DeleteMidiBinding {
+        id: Id,
+    },
+    /// Apply the next `len` commands in the same process cycle. The commands
+    /// are not applied until all of them are in the queue.
+    BeginBatch {
+        len: usize,
+    },
+}
+
+/// A command along with its position in the order that commands were sent.
+/// Sequence numbers start at 1 and increase by 1 with every command.
+pub struct SequencedCommand {
+    pub sequence: u64,
+    pub command: Command,
+}
+
+/// Objects that have been removed from the audio thread. They are sent back so
+/// that they may be deallocated outside of the realtime thread.
+// Boxing `Track` would require an allocation on the audio thread.
+#[allow(clippy::large_enum_variant)]
+pub enum Garbage {
+    Track(Track),
+    PluginInstance(Box<livi::Instance>),
+    MidiClip(MidiClip),
+    AudioClip(AudioClip),
+    Recording(Recording),
+    MidiBinding(MidiBinding),
 }
@@ -1,32 +1,116 @@
-use command::Command;
+use command::{Command, Garbage, SequencedCommand};
+use recording::RecordingSource;
+use smooth::Ramp;
+use std::sync::Arc;
 
+pub mod atom_output;
+pub mod audio_clip;
 pub mod channels;
 pub mod command;
+pub mod meter;
+pub mod midi;
+pub mod midi_learn;
+pub mod overflow;
+pub mod pan;
+pub mod recording;
+pub mod sequencer;
+pub mod smooth;
 pub mod track;
+pub mod transport;
 
 pub type Id = u64;
 
+/// The id of the master track. The master track always exists and receives the
+/// output of all other tracks.
+pub const MASTER_TRACK_ID: Id = 1;
+
+/// The maximum number of tracks, not counting the master track.
+pub const MAX_TRACKS: usize = 128;
+
 #[derive(Copy, Clone, Debug)]
 pub struct RawMidi<'a> {
+    pub port: usize,
     pub frame: usize,
     pub data: &'a [u8],
 }
 
 pub struct IO<'a, M> {
+    /// The audio inputs from the backend. Each element is a single channel.
+    pub audio_in: &'a [&'a [f32]],
     pub audio_out: &'a mut channels::FixedChannels<2>,
     pub midi: M,
+    /// Receives the midi output of tracks that have midi output enabled. The
+    /// buffer is cleared at the start of each cycle.
+    pub midi_out: Option<&'a mut midi::MidiOutputBuffer>,
 }
 
 pub struct PeppermintCore {
-    command_queue: ringbuf::Consumer<Command>,
+    command_queue: ringbuf::Consumer<SequencedCommand>,
+    garbage_queue: ringbuf::Producer<Garbage>,
+    overflows: Arc<overflow::OverflowCounter>,
+    ack_queue: Option<ringbuf::Producer<u64>>,
+    /// The sequence number of the last command that was applied.
+    applied_sequence: u64,
+    /// The last sequence number that was sent to `ack_queue`.
+    acked_sequence: u64,
+    /// The number of commands in the batch that is waiting to be applied.
+    pending_batch_len: usize,
     tracks: Vec<track::Track>,
+    master: track::Track,
+    pan_law: pan::PanLaw,
+    /// The number of samples it takes for gain and pan changes to take full
+    /// effect.
+    smoothing_samples: usize,
+    sample_rate: f64,
+    transport: transport::Transport,
+    recordings: Vec<recording::Recording>,
+    midi_bindings: Vec<midi_learn::MidiBinding>,
+    atom_output_queue: Option<ringbuf::Producer<atom_output::AtomOutputEvent>>,
+    /// If true, the transport position is sent to all plugins on the next
+    /// call to `process`.
+    transport_changed: bool,
+    /// If true, the transport stopped or the playhead jumped since the last
+    /// call to `process`.
+    transport_jumped: bool,
+    /// The indices of `tracks` in the order they should be processed. Tracks
+    /// are processed before the tracks they send to.
+    processing_order: Vec<usize>,
+    /// The longest chain of sends that lead into each track.
+    send_depths: Vec<usize>,
+    processing_order_is_stale: bool,
 }
 
 impl PeppermintCore {
-    pub fn new(command_queue: ringbuf::Consumer<Command>) -> PeppermintCore {
+    /// Create a new `PeppermintCore`. `master` should have the id
+    /// `MASTER_TRACK_ID`.
+    pub fn new(
+        command_queue: ringbuf::Consumer<SequencedCommand>,
+        garbage_queue: ringbuf::Producer<Garbage>,
+        master: track::Track,
+    ) -> PeppermintCore {
+        debug_assert_eq!(master.id(), MASTER_TRACK_ID);
         PeppermintCore {
             command_queue,
-            tracks: Vec::with_capacity(128),
+            garbage_queue,
+            overflows: Arc::default(),
+            ack_queue: None,
+            applied_sequence: 0,
+            acked_sequence: 0,
+            pending_batch_len: 0,
+            tracks: Vec::with_capacity(MAX_TRACKS),
+            master,
+            pan_law: pan::PanLaw::default(),
+            smoothing_samples: 0,
+            sample_rate: 44100.0,
+            transport: transport::Transport::default(),
+            recordings: Vec::with_capacity(16),
+            midi_bindings: Vec::with_capacity(128),
+            atom_output_queue: None,
+            transport_changed: true,
+            transport_jumped: false,
+            processing_order: Vec::with_capacity(MAX_TRACKS),
+            send_depths: Vec::with_capacity(MAX_TRACKS),
+            processing_order_is_stale: false,
         }
     }
 
@@ -36,54 +120,608 @@ impl PeppermintCore {
         samples: usize,
     ) {
         self.handle_command_queue();
+        for midi in io.midi.clone() {
+            if let Some((control, value)) = midi_learn::MidiControl::from_midi(&midi) {
+                self.apply_midi_control(control, value);
+            }
+        }
+        if self.processing_order_is_stale {
+            self.update_processing_order();
+        }
         io.audio_out.clear();
+        let mut midi_out = io.midi_out;
+        if let Some(midi_out) = midi_out.as_mut() {
+            midi_out.clear();
+        }
+        self.master.clear_input();
         for track in self.tracks.iter_mut() {
-            let gain = track.property(track::TrackProperty::Gain);
-            io.audio_out
-                .mix(track.process(samples, io.midi.clone()), gain);
+            track.clear_input();
+            track.mix_audio_input(io.audio_in);
+        }
+        let transport = transport::TransportCycle {
+            position: if self.transport_changed {
+                Some(self.transport.position())
+            } else {
+                None
+            },
+            playing: self.transport.is_playing(),
+            jumped: self.transport_jumped,
+            frame: self.transport.frame(),
+            beat: self.transport.beat(),
+            beats_per_frame: self.transport.beats_per_frame(self.sample_rate),
+        };
+        let any_soloed = self.tracks.iter().any(|t| t.is_soloed());
+        for order_idx in 0..self.processing_order.len() {
+            let track_idx = self.processing_order[order_idx];
+            // Tracks that receive sends are not silenced by solo so that soloed
+            // tracks keep their effects.
+            let is_solo_safe = self.send_depths[track_idx] > 0;
+            let track = &mut self.tracks[track_idx];
+            let is_audible =
+                !track.is_muted() && (!any_soloed || track.is_soloed() || is_solo_safe);
+            let target_gains = if is_audible {
+                track.output_gains(self.pan_law)
+            } else {
+                [0.0; 2]
+            };
+            let fader = track.advance_fader(target_gains, self.smoothing_samples, samples);
+            let output_track_id = track.id();
+            let output = track.process(
+                samples,
+                io.midi.clone(),
+                &transport,
+                self.atom_output_queue.as_mut(),
+            );
+            self.master.mix_input(output, &fader);
+            record(
+                &mut self.recordings,
+                RecordingSource::Track(output_track_id),
+                output,
+                samples,
+            );
+            self.tracks[track_idx].measure_output(&fader, samples);
+            if let Some(midi_out) = midi_out.as_mut() {
+                self.tracks[track_idx].write_midi_output(midi_out);
+            }
+            for send_idx in 0..self.tracks[track_idx].sends().len() {
+                let send = self.tracks[track_idx].sends()[send_idx];
+                let send_ramp = if send.pre_fader {
+                    Ramp::constant([send.level; 2])
+                } else {
+                    fader.scale(send.level)
+                };
+                let destination_idx =
+                    match self.tracks.iter().position(|t| t.id() == send.destination) {
+                        Some(idx) if idx != track_idx => idx,
+                        _ => continue,
+                    };
+                let (source, destination) = pair_mut(&mut self.tracks, track_idx, destination_idx);
+                destination.mix_input(source.output(), &send_ramp);
+            }
+        }
+        let master_gains = if self.master.is_muted() {
+            [0.0; 2]
+        } else {
+            self.master.output_gains(self.pan_law)
+        };
+        let master_fader = self
+            .master
+            .advance_fader(master_gains, self.smoothing_samples, samples);
+        let master_output = self.master.process(
+            samples,
+            std::iter::empty(),
+            &transport,
+            self.atom_output_queue.as_mut(),
+        );
+        record(
+            &mut self.recordings,
+            RecordingSource::Track(MASTER_TRACK_ID),
+            master_output,
+            samples,
+        );
+        io.audio_out.mix_ramped(master_output, &master_fader);
+        self.master.measure_output(&master_fader, samples);
+        if let Some(midi_out) = midi_out.as_mut() {
+            self.master.write_midi_output(midi_out);
         }
+        record(
+            &mut self.recordings,
+            RecordingSource::Output,
+            io.audio_out,
+            samples,
+        );
+        self.transport.advance(samples, self.sample_rate);
+        self.transport_changed = false;
+        self.transport_jumped = false;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.transport_changed = true;
+    }
+
+    /// Copy the atom output of all plugin instances to `queue`.
+    pub fn set_atom_output_queue(
+        &mut self,
+        queue: ringbuf::Producer<atom_output::AtomOutputEvent>,
+    ) {
+        self.atom_output_queue = Some(queue);
+    }
+
+    /// Count refused and dropped objects in `overflows` instead of the
+    /// counter that was created with the core.
+    pub fn set_overflow_counter(&mut self, overflows: Arc<overflow::OverflowCounter>) {
+        self.overflows = overflows;
+    }
+
+    /// Send the sequence number of the last applied command to `queue` after
+    /// the commands of each process cycle are applied.
+    pub fn set_ack_queue(&mut self, queue: ringbuf::Producer<u64>) {
+        self.ack_queue = Some(queue);
+    }
+
+    /// Set the time it takes for gain, pan, and mute changes to take full
+    /// effect.
+    pub fn set_smoothing_samples(&mut self, samples: usize) {
+        self.smoothing_samples = samples;
     }
 
     pub fn set_buffer_size(&mut self, buffer_size: usize) {
-        for track in self.tracks.iter_mut() {
+        for track in iter_all_tracks_mut(&mut self.tracks, &mut self.master) {
             track.set_buffer_size(buffer_size);
         }
     }
 
+    /// Learn `control` on the armed binding, if any, and update the targets of
+    /// all bindings for `control`.
+    fn apply_midi_control(&mut self, control: midi_learn::MidiControl, value: u8) {
+        for binding in self.midi_bindings.iter_mut() {
+            binding.learn(control);
+            let value = match binding.apply(control, value) {
+                Some(value) => value,
+                None => continue,
+            };
+            match binding.target() {
+                midi_learn::MidiTarget::PluginParameter { instance, port } => {
+                    for track in iter_all_tracks_mut(&mut self.tracks, &mut self.master) {
+                        if track.set_instance_control(instance, port, value) {
+                            break;
+                        }
+                    }
+                }
+                midi_learn::MidiTarget::TrackProperty { track, property } => {
+                    if let Some(track) = find_track_mut(&mut self.tracks, &mut self.master, track) {
+                        track.set_property(property, value);
+                    }
+                }
+            }
+        }
+    }
+
     fn handle_command_queue(&mut self) {
-        self.command_queue.pop_each(
-            |c| {
-                match c {
-                    Command::CreateTrack(t) => self.tracks.push(t),
-                    Command::DeleteTrack(track_id) => {
-                        self.tracks.retain(|t| t.id() != track_id);
+        loop {
+            // The commands of a batch are held until all of them are in the
+            // queue so that they are applied in the same process cycle.
+            if self.command_queue.len() < self.pending_batch_len {
+                break;
+            }
+            self.pending_batch_len = 0;
+            let c = match self.command_queue.pop() {
+                Some(c) => c,
+                None => break,
+            };
+            self.handle_command(c.command);
+            // A batch is acknowledged with its last command.
+            if self.pending_batch_len == 0 {
+                self.applied_sequence = c.sequence;
+            }
+        }
+        self.ack_commands();
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::BeginBatch { len } => self.pending_batch_len = len,
+            Command::CreateTrack(t) => {
+                if self.tracks.len() < MAX_TRACKS {
+                    self.tracks.push(t);
+                    self.processing_order_is_stale = true;
+                    self.transport_changed = true;
+                } else {
+                    dispose(&mut self.garbage_queue, &self.overflows, Garbage::Track(t));
+                }
+            }
+            Command::DeleteTrack(track_id) => {
+                if let Some(idx) = self.tracks.iter().position(|t| t.id() == track_id) {
+                    let track = self.tracks.remove(idx);
+                    dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::Track(track),
+                    );
+                }
+                for track in self.tracks.iter_mut() {
+                    track.delete_sends_to(track_id);
+                }
+                self.processing_order_is_stale = true;
+            }
+            Command::UpdateTrack(track_id, property, value) => {
+                if let Some(track) = find_track_mut(&mut self.tracks, &mut self.master, track_id) {
+                    track.set_property(property, value);
+                }
+            }
+            Command::UpdateTrackMidiInput(track_id, midi_input) => {
+                if let Some(track) = find_track_mut(&mut self.tracks, &mut self.master, track_id) {
+                    track.set_midi_input(midi_input);
+                }
+            }
+            Command::UpdateTrackAudioInput(track_id, audio_input) => {
+                if let Some(track) = find_track_mut(&mut self.tracks, &mut self.master, track_id) {
+                    track.set_audio_input(audio_input);
+                }
+            }
+            Command::SetPanLaw(pan_law) => self.pan_law = pan_law,
+            Command::Play => {
+                self.transport.play();
+                self.transport_changed = true;
+            }
+            Command::Stop => {
+                self.transport.stop();
+                self.transport_changed = true;
+                self.transport_jumped = true;
+            }
+            Command::Seek(frame) => {
+                self.transport.seek(frame, self.sample_rate);
+                self.transport_changed = true;
+                self.transport_jumped = true;
+            }
+            Command::SetTempo(tempo) => {
+                self.transport.set_tempo(tempo);
+                self.transport_changed = true;
+            }
+            Command::CreateSend { track, send } => {
+                if let Some(track) = self.tracks.iter_mut().find(|t| t.id() == track) {
+                    if track.push_send(send) {
+                        self.processing_order_is_stale = true;
                     }
-                    Command::UpdateTrack(track_id, property, value) => {
-                        for track in self.tracks.iter_mut() {
-                            if track.id() == track_id {
-                                track.set_property(property, value);
-                                break;
-                            }
-                        }
+                }
+            }
+            Command::UpdateSend {
+                id,
+                level,
+                pre_fader,
+            } => {
+                for track in self.tracks.iter_mut() {
+                    if track.update_send(id, level, pre_fader) {
+                        break;
+                    }
+                }
+            }
+            Command::DeleteSend { id } => {
+                for track in self.tracks.iter_mut() {
+                    if track.delete_send(id) {
+                        self.processing_order_is_stale = true;
+                        break;
                     }
-                    Command::PushPluginInstance {
-                        id,
-                        track,
-                        instance,
-                    } => {
-                        if let Some(track) = self.tracks.iter_mut().find(|t| t.id() == track) {
-                            track.push_instance(id, instance);
+                }
+            }
+            Command::PushPluginInstance {
+                id,
+                track,
+                instance,
+            } => {
+                let rejected = match find_track_mut(&mut self.tracks, &mut self.master, track) {
+                    Some(track) => track.push_instance(id, instance).err(),
+                    None => Some(instance),
+                };
+                match rejected {
+                    Some(instance) => dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::PluginInstance(instance),
+                    ),
+                    None => self.transport_changed = true,
+                }
+            }
+            Command::DeletePluginInstance { id } => {
+                for track in iter_all_tracks_mut(&mut self.tracks, &mut self.master) {
+                    if let Some(instance) = track.delete_instance(id) {
+                        dispose(
+                            &mut self.garbage_queue,
+                            &self.overflows,
+                            Garbage::PluginInstance(instance),
+                        );
+                        break;
+                    }
+                }
+            }
+            Command::MovePluginInstance { id, track, index } => {
+                let moved = find_track_mut(&mut self.tracks, &mut self.master, track)
+                    .map(|t| t.move_instance(id, index))
+                    .unwrap_or(false);
+                // Otherwise the instance is on another track.
+                let container = if moved {
+                    self.transport_changed = true;
+                    None
+                } else {
+                    iter_all_tracks_mut(&mut self.tracks, &mut self.master)
+                        .find_map(|t| t.take_instance(id))
+                };
+                if let Some(container) = container {
+                    let container = match find_track_mut(&mut self.tracks, &mut self.master, track)
+                    {
+                        Some(target) => target.insert_taken_instance(index, container).err(),
+                        None => Some(container),
+                    };
+                    match container {
+                        Some(container) => dispose(
+                            &mut self.garbage_queue,
+                            &self.overflows,
+                            Garbage::PluginInstance(container.into_instance()),
+                        ),
+                        None => self.transport_changed = true,
+                    }
+                }
+            }
+            Command::UpdatePluginInstance { id, port, value } => {
+                for track in iter_all_tracks_mut(&mut self.tracks, &mut self.master) {
+                    if track.set_instance_control(id, port, value) {
+                        break;
+                    }
+                }
+            }
+            Command::UpdatePluginInstanceProperty {
+                id,
+                property,
+                value,
+            } => {
+                for track in iter_all_tracks_mut(&mut self.tracks, &mut self.master) {
+                    if track.set_instance_property(id, property, value) {
+                        break;
+                    }
+                }
+            }
+            Command::SetMidiClip { track, clip } => {
+                match find_track_mut(&mut self.tracks, &mut self.master, track) {
+                    Some(t) => {
+                        if let Some(unused) = t.sequencer_mut().set_clip(clip) {
+                            dispose(
+                                &mut self.garbage_queue,
+                                &self.overflows,
+                                Garbage::MidiClip(unused),
+                            );
                         }
                     }
-                    Command::DeletePluginInstance { id } => {
-                        for track in self.tracks.iter_mut() {
-                            track.delete_instance(id);
+                    None => dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::MidiClip(clip),
+                    ),
+                }
+            }
+            Command::CreateAudioClip { track, clip } => {
+                match find_track_mut(&mut self.tracks, &mut self.master, track) {
+                    Some(t) => {
+                        if let Err(clip) = t.push_audio_clip(clip) {
+                            dispose(
+                                &mut self.garbage_queue,
+                                &self.overflows,
+                                Garbage::AudioClip(clip),
+                            );
                         }
                     }
-                };
-                true
-            },
-            None,
-        );
+                    None => dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::AudioClip(clip),
+                    ),
+                }
+            }
+            Command::UpdateAudioClip { id, region } => {
+                for track in iter_all_tracks_mut(&mut self.tracks, &mut self.master) {
+                    if track.set_audio_clip_region(id, region) {
+                        break;
+                    }
+                }
+            }
+            Command::DeleteAudioClip { id } => {
+                for track in iter_all_tracks_mut(&mut self.tracks, &mut self.master) {
+                    if let Some(clip) = track.delete_audio_clip(id) {
+                        dispose(
+                            &mut self.garbage_queue,
+                            &self.overflows,
+                            Garbage::AudioClip(clip),
+                        );
+                        break;
+                    }
+                }
+            }
+            Command::StartRecording(recording) => {
+                if self.recordings.len() < self.recordings.capacity() {
+                    self.recordings.push(recording);
+                } else {
+                    self.overflows.add_recording();
+                    dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::Recording(recording),
+                    );
+                }
+            }
+            Command::CreateMidiBinding(binding) => {
+                if self.midi_bindings.len() < self.midi_bindings.capacity() {
+                    self.midi_bindings.push(binding);
+                } else {
+                    self.overflows.add_midi_binding();
+                    dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::MidiBinding(binding),
+                    );
+                }
+            }
+            Command::ResetSession(master) => {
+                while let Some(track) = self.tracks.pop() {
+                    dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::Track(track),
+                    );
+                }
+                let old_master = std::mem::replace(&mut self.master, master);
+                dispose(
+                    &mut self.garbage_queue,
+                    &self.overflows,
+                    Garbage::Track(old_master),
+                );
+                while let Some(binding) = self.midi_bindings.pop() {
+                    dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::MidiBinding(binding),
+                    );
+                }
+                self.processing_order_is_stale = true;
+                self.transport_changed = true;
+            }
+            Command::DeleteMidiBinding { id } => {
+                if let Some(idx) = self.midi_bindings.iter().position(|b| b.id() == id) {
+                    let binding = self.midi_bindings.remove(idx);
+                    dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::MidiBinding(binding),
+                    );
+                }
+            }
+            Command::StopRecording { id } => {
+                if let Some(idx) = self.recordings.iter().position(|r| r.id() == id) {
+                    let recording = self.recordings.remove(idx);
+                    dispose(
+                        &mut self.garbage_queue,
+                        &self.overflows,
+                        Garbage::Recording(recording),
+                    );
+                }
+            }
+            Command::DeleteMidiClip { id } => {
+                for track in iter_all_tracks_mut(&mut self.tracks, &mut self.master) {
+                    if let Some(clip) = track.sequencer_mut().delete_clip(id) {
+                        dispose(
+                            &mut self.garbage_queue,
+                            &self.overflows,
+                            Garbage::MidiClip(clip),
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send the sequence number of the last applied command if it has not been
+    /// sent yet. If the queue is full, it is sent on a later cycle.
+    fn ack_commands(&mut self) {
+        if self.applied_sequence == self.acked_sequence {
+            return;
+        }
+        if let Some(queue) = self.ack_queue.as_mut() {
+            if queue.push(self.applied_sequence).is_ok() {
+                self.acked_sequence = self.applied_sequence;
+            }
+        }
+    }
+
+    /// Sort the tracks so that every track is processed before the tracks it
+    /// sends to.
+    fn update_processing_order(&mut self) {
+        self.send_depths.clear();
+        self.send_depths.resize(self.tracks.len(), 0);
+        // A chain of sends can not be longer than the number of tracks. The
+        // bound also guards against cycles.
+        for _ in 0..self.tracks.len() {
+            let mut changed = false;
+            for (source_idx, source) in self.tracks.iter().enumerate() {
+                for send in source.sends() {
+                    let destination_idx =
+                        match self.tracks.iter().position(|t| t.id() == send.destination) {
+                            Some(idx) => idx,
+                            None => continue,
+                        };
+                    let depth = self.send_depths[source_idx] + 1;
+                    if self.send_depths[destination_idx] < depth {
+                        self.send_depths[destination_idx] = depth;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        self.processing_order.clear();
+        self.processing_order.extend(0..self.tracks.len());
+        let send_depths = &self.send_depths;
+        self.processing_order
+            .sort_unstable_by_key(|idx| (send_depths[*idx], *idx));
+        self.processing_order_is_stale = false;
+    }
+}
+
+/// Find the track with `id` within `tracks` or `master`.
+fn find_track_mut<'a>(
+    tracks: &'a mut [track::Track],
+    master: &'a mut track::Track,
+    id: Id,
+) -> Option<&'a mut track::Track> {
+    if master.id() == id {
+        Some(master)
+    } else {
+        tracks.iter_mut().find(|t| t.id() == id)
+    }
+}
+
+/// Write `audio` to all recordings of `source`.
+fn record(
+    recordings: &mut [recording::Recording],
+    source: RecordingSource,
+    audio: &channels::FixedChannels<2>,
+    samples: usize,
+) {
+    for recording in recordings.iter_mut().filter(|r| r.source() == source) {
+        recording.write(audio, samples);
+    }
+}
+
+/// Iterate over all `tracks` followed by the `master` track.
+fn iter_all_tracks_mut<'a>(
+    tracks: &'a mut [track::Track],
+    master: &'a mut track::Track,
+) -> impl Iterator<Item = &'a mut track::Track> {
+    tracks.iter_mut().chain(std::iter::once(master))
+}
+
+/// Get mutable references to the elements at `a` and `b`. `a` and `b` must not
+/// be equal.
+fn pair_mut<T>(slice: &mut [T], a: usize, b: usize) -> (&mut T, &mut T) {
+    debug_assert_ne!(a, b);
+    if a < b {
+        let (left, right) = slice.split_at_mut(b);
+        (&mut left[a], &mut right[0])
+    } else {
+        let (left, right) = slice.split_at_mut(a);
+        (&mut right[0], &mut left[b])
+    }
+}
+
+/// Send `garbage` to be deallocated outside of the audio thread. If the queue is
+/// full, then `garbage` is dropped in place and counted in `overflows`.
+fn dispose(
+    garbage_queue: &mut ringbuf::Producer<Garbage>,
+    overflows: &overflow::OverflowCounter,
+    garbage: Garbage,
+) {
+    if garbage_queue.push(garbage).is_err() {
+        overflows.add_garbage();
     }
 }
@@ -0,0 +1,103 @@
+use crate::channels::FixedChannels;
+use crate::smooth::Ramp;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of samples that each published reading covers. This is about
+/// 46ms at 44.1kHz.
+const METER_WINDOW_SAMPLES: usize = 2048;
+
+/// The levels of a stereo signal. Readings are published by the audio thread
+/// and may be read from any thread without locking.
+#[derive(Debug, Default)]
+pub struct Meter {
+    peak: [AtomicU32; 2],
+    rms: [AtomicU32; 2],
+    clips: AtomicU64,
+}
+
+/// A snapshot of a `Meter`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MeterReading {
+    /// The highest absolute sample value of each channel.
+    pub peak: [f32; 2],
+    /// The root mean square of each channel.
+    pub rms: [f32; 2],
+    /// The number of windows that contained a sample at or above 1.0. This
+    /// only increases.
+    pub clips: u64,
+}
+
+impl Meter {
+    pub fn read(&self) -> MeterReading {
+        let load = |values: &[AtomicU32; 2]| {
+            [
+                f32::from_bits(values[0].load(Ordering::Relaxed)),
+                f32::from_bits(values[1].load(Ordering::Relaxed)),
+            ]
+        };
+        MeterReading {
+            peak: load(&self.peak),
+            rms: load(&self.rms),
+            clips: self.clips.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Measures audio on the audio thread and publishes the results to a `Meter`.
+#[derive(Debug, Default)]
+pub struct MeterState {
+    meter: Arc<Meter>,
+    peak: [f32; 2],
+    sum_squares: [f32; 2],
+    samples: usize,
+}
+
+impl MeterState {
+    /// The meter that readings are published to.
+    pub fn meter(&self) -> Arc<Meter> {
+        self.meter.clone()
+    }
+
+    /// Measure the first `samples` of `audio` with the gain of each channel
+    /// following `ramp`.
+    pub fn measure(&mut self, audio: &FixedChannels<2>, ramp: &Ramp<2>, samples: usize) {
+        for (channel, src) in audio.iter_channels().enumerate() {
+            let start = ramp.start[channel];
+            let end = ramp.end[channel];
+            let step = if ramp.samples > 0 {
+                (end - start) / ramp.samples as f32
+            } else {
+                0.0
+            };
+            for (idx, x) in src.iter().take(samples).enumerate() {
+                let gain = if idx < ramp.samples {
+                    start + step * idx as f32
+                } else {
+                    end
+                };
+                let x = *x * gain;
+                self.peak[channel] = self.peak[channel].max(x.abs());
+                self.sum_squares[channel] += x * x;
+            }
+        }
+        self.samples += samples;
+        if self.samples >= METER_WINDOW_SAMPLES {
+            self.publish();
+        }
+    }
+
+    fn publish(&mut self) {
+        for channel in 0..2 {
+            let rms = (self.sum_squares[channel] / self.samples as f32).sqrt();
+            self.meter.peak[channel].store(self.peak[channel].to_bits(), Ordering::Relaxed);
+            self.meter.rms[channel].store(rms.to_bits(), Ordering::Relaxed);
+        }
+        if self.peak.iter().any(|p| *p >= 1.0) {
+            self.meter.clips.fetch_add(1, Ordering::Relaxed);
+        }
+        self.peak = [0.0; 2];
+        self.sum_squares = [0.0; 2];
+        self.samples = 0;
+    }
+}
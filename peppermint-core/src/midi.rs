@@ -0,0 +1,157 @@
+use crate::{Id, RawMidi};
+
+/// Selects which midi events are fed into a track.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MidiInput {
+    /// The index of the backend midi port to take events from.
+    pub port: usize,
+
+    /// The channel, from 0 to 15, to accept events from or `None` to accept
+    /// events from all channels.
+    pub channel: Option<u8>,
+
+    /// The inclusive range of notes to accept or `None` to accept all notes.
+    pub note_range: Option<(u8, u8)>,
+}
+
+impl MidiInput {
+    /// Returns true if `midi` should be passed to the track.
+    pub fn accepts(&self, midi: &RawMidi) -> bool {
+        if midi.port != self.port {
+            return false;
+        }
+        let status = match midi.data.first() {
+            Some(s) => *s,
+            None => return false,
+        };
+        // System messages do not belong to a channel.
+        if !(0x80..0xF0).contains(&status) {
+            return true;
+        }
+        if let Some(channel) = self.channel {
+            if status & 0x0F != channel {
+                return false;
+            }
+        }
+        if let Some((low, high)) = self.note_range {
+            // Note off, note on, and polyphonic aftertouch.
+            if (0x80..0xB0).contains(&status) {
+                return match midi.data.get(1) {
+                    Some(note) => (low..=high).contains(note),
+                    None => false,
+                };
+            }
+        }
+        true
+    }
+}
+
+/// The largest midi message that can be output. Larger messages, such as
+/// sysex, are dropped.
+pub const MAX_MIDI_OUTPUT_SIZE: usize = 3;
+
+/// A midi message that was output by a track.
+#[derive(Copy, Clone, Debug)]
+pub struct MidiOutputEvent {
+    pub track: Id,
+    /// The frame within the block.
+    pub frame: usize,
+    len: usize,
+    data: [u8; MAX_MIDI_OUTPUT_SIZE],
+}
+
+impl MidiOutputEvent {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+/// Collects the midi output of all tracks for a single processing cycle.
+/// Events are kept sorted by frame.
+pub struct MidiOutputBuffer {
+    events: Vec<MidiOutputEvent>,
+}
+
+impl MidiOutputBuffer {
+    /// Create a buffer that holds up to `capacity` events. Events past the
+    /// capacity are dropped.
+    pub fn new(capacity: usize) -> MidiOutputBuffer {
+        MidiOutputBuffer {
+            events: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// Add an event to the buffer. Events on the same frame are kept in the
+    /// order that they were pushed.
+    pub fn push(&mut self, track: Id, frame: usize, data: &[u8]) {
+        if self.events.len() == self.events.capacity() || data.len() > MAX_MIDI_OUTPUT_SIZE {
+            return;
+        }
+        let mut event = MidiOutputEvent {
+            track,
+            frame,
+            len: data.len(),
+            data: [0; MAX_MIDI_OUTPUT_SIZE],
+        };
+        event.data[..data.len()].copy_from_slice(data);
+        let idx = self.events.partition_point(|e| e.frame <= frame);
+        self.events.insert(idx, event);
+    }
+
+    pub fn events(&self) -> &[MidiOutputEvent] {
+        &self.events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn midi(port: usize, data: &[u8]) -> RawMidi<'_> {
+        RawMidi {
+            port,
+            frame: 0,
+            data,
+        }
+    }
+
+    #[test]
+    fn default_accepts_everything_on_port_0() {
+        let input = MidiInput::default();
+        assert!(input.accepts(&midi(0, &[0x90, 60, 100])));
+        assert!(input.accepts(&midi(0, &[0xBF, 1, 64])));
+        assert!(!input.accepts(&midi(1, &[0x90, 60, 100])));
+        assert!(!input.accepts(&midi(0, &[])));
+    }
+
+    #[test]
+    fn channel_filter() {
+        let input = MidiInput {
+            channel: Some(2),
+            ..MidiInput::default()
+        };
+        assert!(input.accepts(&midi(0, &[0x92, 60, 100])));
+        assert!(!input.accepts(&midi(0, &[0x93, 60, 100])));
+        // System messages do not belong to a channel.
+        assert!(input.accepts(&midi(0, &[0xF8])));
+    }
+
+    #[test]
+    fn note_range_filter() {
+        let input = MidiInput {
+            note_range: Some((48, 59)),
+            ..MidiInput::default()
+        };
+        assert!(input.accepts(&midi(0, &[0x90, 48, 100])));
+        assert!(input.accepts(&midi(0, &[0x80, 59, 0])));
+        assert!(input.accepts(&midi(0, &[0xA0, 50, 10])));
+        assert!(!input.accepts(&midi(0, &[0x90, 60, 100])));
+        assert!(!input.accepts(&midi(0, &[0x90])));
+        // Controllers are not notes.
+        assert!(input.accepts(&midi(0, &[0xB0, 60, 100])));
+    }
+}
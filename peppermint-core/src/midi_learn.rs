@@ -0,0 +1,152 @@
+use crate::track::TrackProperty;
+use crate::{Id, RawMidi};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// The value that is controlled by a midi binding.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MidiTarget {
+    PluginParameter { instance: Id, port: livi::PortIndex },
+    TrackProperty { track: Id, property: TrackProperty },
+}
+
+/// Identifies a continuous controller on a backend midi port.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MidiControl {
+    /// The index of the backend midi port.
+    pub port: usize,
+    /// The channel from 0 to 15.
+    pub channel: u8,
+    /// The controller number from 0 to 127.
+    pub controller: u8,
+}
+
+impl MidiControl {
+    /// Returns the control and value of `midi` if it is a control change
+    /// message.
+    pub fn from_midi(midi: &RawMidi) -> Option<(MidiControl, u8)> {
+        match *midi.data {
+            [status, controller, value] if status & 0xF0 == 0xB0 => Some((
+                MidiControl {
+                    port: midi.port,
+                    channel: status & 0x0F,
+                    controller,
+                },
+                value,
+            )),
+            _ => None,
+        }
+    }
+
+    fn pack(&self) -> u32 {
+        ((self.port as u32) << 16) | ((self.channel as u32) << 8) | self.controller as u32
+    }
+
+    fn unpack(packed: u32) -> MidiControl {
+        MidiControl {
+            port: (packed >> 16) as usize,
+            channel: (packed >> 8) as u8,
+            controller: packed as u8,
+        }
+    }
+}
+
+/// The state of a binding that is shared with other threads.
+#[derive(Debug)]
+pub struct MidiBindingState {
+    control: AtomicU32,
+    value: AtomicU32,
+}
+
+impl Default for MidiBindingState {
+    fn default() -> MidiBindingState {
+        MidiBindingState {
+            control: AtomicU32::new(u32::MAX),
+            value: AtomicU32::new(f32::NAN.to_bits()),
+        }
+    }
+}
+
+impl MidiBindingState {
+    /// The control that was learned or `None` if the binding is still armed.
+    pub fn control(&self) -> Option<MidiControl> {
+        match self.control.load(Ordering::Acquire) {
+            u32::MAX => None,
+            packed => Some(MidiControl::unpack(packed)),
+        }
+    }
+
+    /// The last value that was set by the binding or `None` if no control
+    /// change has been received yet.
+    pub fn value(&self) -> Option<f32> {
+        let value = f32::from_bits(self.value.load(Ordering::Relaxed));
+        if value.is_nan() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+/// Binds a midi control to a target. A binding without a control is armed and
+/// learns the next control change that arrives.
+pub struct MidiBinding {
+    id: Id,
+    target: MidiTarget,
+    min: f32,
+    max: f32,
+    control: Option<MidiControl>,
+    state: Arc<MidiBindingState>,
+}
+
+impl MidiBinding {
+    /// Create a binding that scales control values to the range `min` to
+    /// `max`. If `control` is `None`, the control is learned.
+    pub fn new(
+        id: Id,
+        target: MidiTarget,
+        min: f32,
+        max: f32,
+        control: Option<MidiControl>,
+        state: Arc<MidiBindingState>,
+    ) -> MidiBinding {
+        if let Some(control) = control {
+            state.control.store(control.pack(), Ordering::Release);
+        }
+        MidiBinding {
+            id,
+            target,
+            min,
+            max,
+            control,
+            state,
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn target(&self) -> MidiTarget {
+        self.target
+    }
+
+    /// Bind the binding to `control` if it is armed.
+    pub fn learn(&mut self, control: MidiControl) {
+        if self.control.is_none() {
+            self.control = Some(control);
+            self.state.control.store(control.pack(), Ordering::Release);
+        }
+    }
+
+    /// Returns the target value for a control change of `value` on `control`
+    /// or `None` if the control is not bound.
+    pub fn apply(&self, control: MidiControl, value: u8) -> Option<f32> {
+        if self.control != Some(control) {
+            return None;
+        }
+        let value = self.min + (self.max - self.min) * value as f32 / 127.0;
+        self.state.value.store(value.to_bits(), Ordering::Relaxed);
+        Some(value)
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts events where the audio thread had to refuse or drop something
+/// because a preallocated buffer was full. The audio thread only increments
+/// the counters, reporting them is left to a thread that is not realtime.
+#[derive(Debug, Default)]
+pub struct OverflowCounter {
+    garbage: AtomicU64,
+    recordings: AtomicU64,
+    midi_bindings: AtomicU64,
+}
+
+/// The number of overflows since the last call to `OverflowCounter::take`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Overflows {
+    /// Objects that were deallocated on the audio thread because the garbage
+    /// queue was full.
+    pub garbage: u64,
+    /// Recordings that were not started because too many were running.
+    pub recordings: u64,
+    /// Midi bindings that were not created because there were too many.
+    pub midi_bindings: u64,
+}
+
+impl OverflowCounter {
+    /// Return the counts and reset them to zero.
+    pub fn take(&self) -> Overflows {
+        Overflows {
+            garbage: self.garbage.swap(0, Ordering::Relaxed),
+            recordings: self.recordings.swap(0, Ordering::Relaxed),
+            midi_bindings: self.midi_bindings.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn add_garbage(&self) {
+        self.garbage.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_recording(&self) {
+        self.recordings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_midi_binding(&self) {
+        self.midi_bindings.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_resets_counts() {
+        let counter = OverflowCounter::default();
+        counter.add_garbage();
+        counter.add_garbage();
+        counter.add_midi_binding();
+        assert_eq!(
+            counter.take(),
+            Overflows {
+                garbage: 2,
+                recordings: 0,
+                midi_bindings: 1,
+            }
+        );
+        assert_eq!(counter.take(), Overflows::default());
+    }
+}
@@ -0,0 +1,81 @@
+use std::f32::consts::FRAC_PI_2;
+
+/// Determines how the left and right gains are derived from a pan position.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PanLaw {
+    /// Sine/cosine panning. A centered signal is attenuated by 3dB.
+    #[default]
+    ConstantPower,
+
+    /// The geometric mean of constant power and linear panning. A centered
+    /// signal is attenuated by 4.5dB.
+    Compromise,
+
+    /// Linear panning. A centered signal is attenuated by 6dB.
+    Linear,
+}
+
+impl PanLaw {
+    /// Get the left and right gains for `pan`. `pan` ranges from `-1.0` (hard
+    /// left) to `1.0` (hard right).
+    pub fn gains(self, pan: f32) -> [f32; 2] {
+        let position = (pan.clamp(-1.0, 1.0) + 1.0) * 0.5;
+        let linear = [1.0 - position, position];
+        let constant_power = [(position * FRAC_PI_2).cos(), (position * FRAC_PI_2).sin()];
+        match self {
+            PanLaw::ConstantPower => constant_power,
+            PanLaw::Compromise => [
+                (linear[0] * constant_power[0]).sqrt(),
+                (linear[1] * constant_power[1]).sqrt(),
+            ],
+            PanLaw::Linear => linear,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_gains(law: PanLaw, pan: f32, expected: [f32; 2]) {
+        let gains = law.gains(pan);
+        for (gain, expected) in gains.iter().zip(expected.iter()) {
+            assert!(
+                (gain - expected).abs() < 1e-6,
+                "{:?} at {} is {:?} instead of {:?}",
+                law,
+                pan,
+                gains,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn hard_pan_is_the_same_for_all_laws() {
+        for law in [PanLaw::ConstantPower, PanLaw::Compromise, PanLaw::Linear] {
+            assert_gains(law, -1.0, [1.0, 0.0]);
+            assert_gains(law, 1.0, [0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn center_attenuation() {
+        let db = |gain: f32| 20.0 * gain.log10();
+        for (law, expected_db) in [
+            (PanLaw::ConstantPower, -3.01),
+            (PanLaw::Compromise, -4.52),
+            (PanLaw::Linear, -6.02),
+        ] {
+            let [left, right] = law.gains(0.0);
+            assert_eq!(left, right);
+            assert!((db(left) - expected_db).abs() < 0.01, "{:?}", law);
+        }
+    }
+
+    #[test]
+    fn pan_is_clamped() {
+        assert_gains(PanLaw::Linear, -2.0, [1.0, 0.0]);
+        assert_gains(PanLaw::Linear, 2.0, [0.0, 1.0]);
+    }
+}
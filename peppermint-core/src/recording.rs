@@ -0,0 +1,66 @@
+use crate::channels::FixedChannels;
+use crate::Id;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The audio that a `Recording` captures.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordingSource {
+    /// The final output that is sent to the audio backend.
+    Output,
+    /// The output of a track's plugins, before its gain and pan are applied.
+    Track(Id),
+}
+
+/// Sends audio from the audio thread to a writer thread.
+pub struct Recording {
+    id: Id,
+    source: RecordingSource,
+    frames: ringbuf::Producer<[f32; 2]>,
+    /// The number of frames that did not fit in `frames`.
+    dropped_frames: Arc<AtomicU64>,
+}
+
+impl Recording {
+    pub fn new(
+        id: Id,
+        source: RecordingSource,
+        frames: ringbuf::Producer<[f32; 2]>,
+        dropped_frames: Arc<AtomicU64>,
+    ) -> Recording {
+        Recording {
+            id,
+            source,
+            frames,
+            dropped_frames,
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn source(&self) -> RecordingSource {
+        self.source
+    }
+
+    /// Send the first `samples` frames of `audio` to the writer thread. Frames
+    /// are dropped if the writer has fallen behind.
+    pub fn write(&mut self, audio: &FixedChannels<2>, samples: usize) {
+        let mut channels = audio.iter_channels();
+        let (left, right) = match (channels.next(), channels.next()) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return,
+        };
+        let samples = samples.min(left.len());
+        let mut frames = left[..samples]
+            .iter()
+            .zip(right[..samples].iter())
+            .map(|(l, r)| [*l, *r]);
+        let written = self.frames.push_iter(&mut frames);
+        if written < samples {
+            self.dropped_frames
+                .fetch_add((samples - written) as u64, Ordering::Relaxed);
+        }
+    }
+}
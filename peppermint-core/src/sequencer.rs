@@ -0,0 +1,335 @@
+use crate::transport::TransportCycle;
+use crate::Id;
+
+/// The maximum number of midi clips on a single track.
+pub const MAX_MIDI_CLIPS: usize = 64;
+
+/// A note within a `MidiClip`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClipNote {
+    /// The start of the note in beats, relative to the start of the clip.
+    pub start: f64,
+    /// The length of the note in beats.
+    pub length: f64,
+    pub pitch: u8,
+    pub velocity: u8,
+    /// The channel from 0 to 15.
+    pub channel: u8,
+}
+
+/// A sequence of notes placed on the timeline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiClip {
+    pub id: Id,
+    /// The start of the clip in beats.
+    pub start: f64,
+    /// The length of the clip in beats. Notes are cut off at the end of the
+    /// clip.
+    pub length: f64,
+    pub notes: Vec<ClipNote>,
+}
+
+/// A midi event produced by a `Sequencer`.
+#[derive(Copy, Clone, Debug)]
+pub struct SequencerEvent {
+    pub frame: usize,
+    pub data: [u8; 3],
+    order: EventOrder,
+}
+
+/// The order of events within the same frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum EventOrder {
+    /// Note offs come first so that repeated notes are retriggered.
+    NoteOff,
+    NoteOn,
+    /// A note that is shorter than a frame is released after it starts.
+    ShortNoteOff,
+}
+
+/// Plays back the midi clips of a track.
+pub struct Sequencer {
+    clips: Vec<MidiClip>,
+    events: Vec<SequencerEvent>,
+    /// A bitset of notes that are currently on, indexed by channel.
+    sounding: [u128; 16],
+    /// If true, all sounding notes are released on the next call to `render`.
+    release_pending: bool,
+}
+
+impl Default for Sequencer {
+    fn default() -> Sequencer {
+        Sequencer {
+            clips: Vec::with_capacity(MAX_MIDI_CLIPS),
+            events: Vec::with_capacity(1024),
+            sounding: [0; 16],
+            release_pending: false,
+        }
+    }
+}
+
+impl Sequencer {
+    /// Add `clip` or replace the clip with the same id. The replaced clip is
+    /// returned. If there are already `MAX_MIDI_CLIPS` clips, `clip` is
+    /// returned instead of being added.
+    pub fn set_clip(&mut self, clip: MidiClip) -> Option<MidiClip> {
+        let full = self.clips.len() >= MAX_MIDI_CLIPS;
+        match self.clips.iter_mut().find(|c| c.id == clip.id) {
+            Some(existing) => {
+                self.release_pending = true;
+                Some(std::mem::replace(existing, clip))
+            }
+            None if full => Some(clip),
+            None => {
+                self.release_pending = true;
+                self.clips.push(clip);
+                None
+            }
+        }
+    }
+
+    pub fn delete_clip(&mut self, id: Id) -> Option<MidiClip> {
+        let idx = self.clips.iter().position(|c| c.id == id)?;
+        self.release_pending = true;
+        Some(self.clips.remove(idx))
+    }
+
+    /// Produce the events for the next `samples` frames. The events are sorted
+    /// by frame and can be retrieved with `events`.
+    pub fn render(&mut self, transport: &TransportCycle, samples: usize) {
+        self.events.clear();
+        if self.release_pending || transport.jumped || !transport.playing {
+            self.release_pending = false;
+            self.release_all();
+        }
+        if !transport.playing || transport.beats_per_frame <= 0.0 || samples == 0 {
+            return;
+        }
+        let block_start = transport.beat;
+        let block_end = block_start + samples as f64 * transport.beats_per_frame;
+        let to_frame = |beat: f64| {
+            (((beat - block_start) / transport.beats_per_frame) as usize).min(samples - 1)
+        };
+        let in_block = |beat: f64| (block_start..block_end).contains(&beat);
+        // Note offs are handled first so that a note that ends where another
+        // note of the same pitch starts does not release the new note.
+        for (clip, note) in self
+            .clips
+            .iter()
+            .flat_map(|c| clip_notes(c, block_start, block_end))
+        {
+            let (note_on, note_off) = note_span(clip, note);
+            if note_off > note_on && in_block(note_off) {
+                let channel = note.channel & 0x0F;
+                self.sounding[channel as usize] &= !(1u128 << (note.pitch & 0x7F));
+                let frame = to_frame(note_off);
+                let order = if in_block(note_on) && to_frame(note_on) == frame {
+                    EventOrder::ShortNoteOff
+                } else {
+                    EventOrder::NoteOff
+                };
+                push_event(
+                    &mut self.events,
+                    frame,
+                    [0x80 | channel, note.pitch, 0],
+                    order,
+                );
+            }
+        }
+        for (clip, note) in self
+            .clips
+            .iter()
+            .flat_map(|c| clip_notes(c, block_start, block_end))
+        {
+            let (note_on, note_off) = note_span(clip, note);
+            if note_off > note_on && in_block(note_on) {
+                let channel = note.channel & 0x0F;
+                if !in_block(note_off) {
+                    self.sounding[channel as usize] |= 1u128 << (note.pitch & 0x7F);
+                }
+                push_event(
+                    &mut self.events,
+                    to_frame(note_on),
+                    [0x90 | channel, note.pitch, note.velocity],
+                    EventOrder::NoteOn,
+                );
+            }
+        }
+        self.events.sort_unstable_by_key(|e| (e.frame, e.order));
+    }
+
+    /// The events produced by the last call to `render`.
+    pub fn events(&self) -> &[SequencerEvent] {
+        &self.events
+    }
+
+    /// Add note off events for all sounding notes.
+    fn release_all(&mut self) {
+        for (channel, sounding) in self.sounding.iter_mut().enumerate() {
+            for pitch in 0..128u8 {
+                if *sounding & (1u128 << pitch) != 0 {
+                    push_event(
+                        &mut self.events,
+                        0,
+                        [0x80 | channel as u8, pitch, 0],
+                        EventOrder::NoteOff,
+                    );
+                }
+            }
+            *sounding = 0;
+        }
+    }
+}
+
+/// Iterate through the notes of `clip` if the clip overlaps the range of beats.
+fn clip_notes(
+    clip: &MidiClip,
+    block_start: f64,
+    block_end: f64,
+) -> impl Iterator<Item = (&MidiClip, &ClipNote)> {
+    let overlaps = clip.start < block_end && clip.start + clip.length >= block_start;
+    let notes = if overlaps { &clip.notes[..] } else { &[] };
+    notes.iter().map(move |n| (clip, n))
+}
+
+/// The beats at which the note turns on and off, cut off by the end of the
+/// clip.
+fn note_span(clip: &MidiClip, note: &ClipNote) -> (f64, f64) {
+    let clip_end = clip.start + clip.length;
+    let note_on = clip.start + note.start;
+    let note_off = (note_on + note.length).min(clip_end);
+    (note_on, note_off)
+}
+
+/// Push `data` to `events` unless it would require an allocation.
+fn push_event(events: &mut Vec<SequencerEvent>, frame: usize, data: [u8; 3], order: EventOrder) {
+    if events.len() < events.capacity() {
+        events.push(SequencerEvent { frame, data, order });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One beat every 128 frames.
+    const BEATS_PER_FRAME: f64 = 1.0 / 128.0;
+
+    fn cycle(frame: u64, jumped: bool) -> TransportCycle {
+        TransportCycle {
+            position: None,
+            playing: true,
+            jumped,
+            frame,
+            beat: frame as f64 * BEATS_PER_FRAME,
+            beats_per_frame: BEATS_PER_FRAME,
+        }
+    }
+
+    fn note(start: f64, length: f64, pitch: u8) -> ClipNote {
+        ClipNote {
+            start,
+            length,
+            pitch,
+            velocity: 100,
+            channel: 0,
+        }
+    }
+
+    fn sequencer(notes: Vec<ClipNote>) -> Sequencer {
+        let mut sequencer = Sequencer::default();
+        sequencer.set_clip(MidiClip {
+            id: 1,
+            start: 0.0,
+            length: 4.0,
+            notes,
+        });
+        sequencer
+    }
+
+    fn events(sequencer: &Sequencer) -> Vec<(usize, [u8; 3])> {
+        sequencer
+            .events()
+            .iter()
+            .map(|e| (e.frame, e.data))
+            .collect()
+    }
+
+    #[test]
+    fn note_spans_blocks() {
+        let mut sequencer = sequencer(vec![note(0.25, 1.0, 60)]);
+        sequencer.render(&cycle(0, false), 64);
+        assert_eq!(events(&sequencer), vec![(32, [0x90, 60, 100])]);
+        sequencer.render(&cycle(64, false), 64);
+        assert_eq!(events(&sequencer), vec![]);
+        sequencer.render(&cycle(128, false), 64);
+        assert_eq!(events(&sequencer), vec![(32, [0x80, 60, 0])]);
+    }
+
+    #[test]
+    fn note_is_cut_off_at_clip_end() {
+        let mut sequencer = sequencer(vec![note(3.5, 2.0, 60)]);
+        sequencer.render(&cycle(416, false), 64);
+        assert_eq!(events(&sequencer), vec![(32, [0x90, 60, 100])]);
+        sequencer.render(&cycle(480, false), 64);
+        assert_eq!(events(&sequencer), vec![(32, [0x80, 60, 0])]);
+    }
+
+    #[test]
+    fn jumping_back_releases_and_retriggers() {
+        let mut sequencer = sequencer(vec![note(0.0, 2.0, 60)]);
+        sequencer.render(&cycle(0, false), 64);
+        assert_eq!(events(&sequencer), vec![(0, [0x90, 60, 100])]);
+        sequencer.render(&cycle(0, true), 64);
+        assert_eq!(
+            events(&sequencer),
+            vec![(0, [0x80, 60, 0]), (0, [0x90, 60, 100])]
+        );
+    }
+
+    #[test]
+    fn stopping_releases_sounding_notes() {
+        let mut sequencer = sequencer(vec![note(0.0, 2.0, 60)]);
+        sequencer.render(&cycle(0, false), 64);
+        let stopped = TransportCycle {
+            playing: false,
+            ..cycle(64, true)
+        };
+        sequencer.render(&stopped, 64);
+        assert_eq!(events(&sequencer), vec![(0, [0x80, 60, 0])]);
+        sequencer.render(&stopped, 64);
+        assert_eq!(events(&sequencer), vec![]);
+    }
+
+    #[test]
+    fn repeated_note_is_retriggered() {
+        let mut sequencer = sequencer(vec![note(0.0, 0.5, 60), note(0.5, 0.5, 60)]);
+        sequencer.render(&cycle(0, false), 192);
+        assert_eq!(
+            events(&sequencer),
+            vec![
+                (0, [0x90, 60, 100]),
+                (64, [0x80, 60, 0]),
+                (64, [0x90, 60, 100]),
+                (128, [0x80, 60, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn note_shorter_than_a_frame_is_released_after_it_starts() {
+        let mut sequencer = sequencer(vec![note(0.0, 0.5, 60), note(0.5, 0.001, 60)]);
+        sequencer.render(&cycle(0, false), 128);
+        assert_eq!(
+            events(&sequencer),
+            vec![
+                (0, [0x90, 60, 100]),
+                (64, [0x80, 60, 0]),
+                (64, [0x90, 60, 100]),
+                (64, [0x80, 60, 0]),
+            ]
+        );
+        sequencer.render(&cycle(128, false), 64);
+        assert_eq!(events(&sequencer), vec![]);
+    }
+}
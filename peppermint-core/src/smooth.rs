@@ -0,0 +1,157 @@
+/// Linearly moves a group of values towards a target over a number of samples.
+/// This is used to avoid audible steps when values such as gain change.
+#[derive(Copy, Clone, Debug)]
+pub struct Smoothed<const N: usize> {
+    current: [f32; N],
+    target: [f32; N],
+    step: [f32; N],
+    remaining: usize,
+}
+
+/// The values of a `Smoothed` over a single block.
+#[derive(Copy, Clone, Debug)]
+pub struct Ramp<const N: usize> {
+    pub start: [f32; N],
+    pub end: [f32; N],
+    /// The number of samples it takes to go from `start` to `end`. All samples
+    /// after this use `end`.
+    pub samples: usize,
+}
+
+impl<const N: usize> Smoothed<N> {
+    pub fn new(value: [f32; N]) -> Smoothed<N> {
+        Smoothed {
+            current: value,
+            target: value,
+            step: [0.0; N],
+            remaining: 0,
+        }
+    }
+
+    /// Start moving towards `target`. The target is reached after
+    /// `smoothing_samples` samples. Ramps that are in progress continue from
+    /// their current value.
+    pub fn set_target(&mut self, target: [f32; N], smoothing_samples: usize) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        if smoothing_samples == 0 {
+            self.current = target;
+            self.remaining = 0;
+            return;
+        }
+        for ((step, current), target) in self
+            .step
+            .iter_mut()
+            .zip(self.current.iter())
+            .zip(target.iter())
+        {
+            *step = (*target - *current) / smoothing_samples as f32;
+        }
+        self.remaining = smoothing_samples;
+    }
+
+    /// Advance by `samples` and return the ramp that covers them.
+    pub fn advance(&mut self, samples: usize) -> Ramp<N> {
+        let start = self.current;
+        let ramp_samples = samples.min(self.remaining);
+        self.remaining -= ramp_samples;
+        if self.remaining == 0 {
+            self.current = self.target;
+        } else {
+            for (current, step) in self.current.iter_mut().zip(self.step.iter()) {
+                *current += *step * ramp_samples as f32;
+            }
+        }
+        Ramp {
+            start,
+            end: self.current,
+            samples: ramp_samples,
+        }
+    }
+}
+
+impl<const N: usize> Ramp<N> {
+    /// A ramp that stays at `value`.
+    pub fn constant(value: [f32; N]) -> Ramp<N> {
+        Ramp {
+            start: value,
+            end: value,
+            samples: 0,
+        }
+    }
+
+    /// Multiply all values by `amount`.
+    pub fn scale(mut self, amount: f32) -> Ramp<N> {
+        for x in self.start.iter_mut().chain(self.end.iter_mut()) {
+            *x *= amount;
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_value_is_constant() {
+        let mut smoothed = Smoothed::new([0.5]);
+        let ramp = smoothed.advance(64);
+        assert_eq!(ramp.start, [0.5]);
+        assert_eq!(ramp.end, [0.5]);
+        assert_eq!(ramp.samples, 0);
+    }
+
+    #[test]
+    fn target_is_reached_after_smoothing_samples() {
+        let mut smoothed = Smoothed::new([0.0, 1.0]);
+        smoothed.set_target([1.0, 0.0], 128);
+        let ramp = smoothed.advance(64);
+        assert_eq!(ramp.start, [0.0, 1.0]);
+        assert_eq!(ramp.end, [0.5, 0.5]);
+        assert_eq!(ramp.samples, 64);
+        let ramp = smoothed.advance(100);
+        assert_eq!(ramp.start, [0.5, 0.5]);
+        assert_eq!(ramp.end, [1.0, 0.0]);
+        assert_eq!(ramp.samples, 64);
+        let ramp = smoothed.advance(64);
+        assert_eq!(ramp.start, [1.0, 0.0]);
+        assert_eq!(ramp.samples, 0);
+    }
+
+    #[test]
+    fn new_target_continues_from_current_value() {
+        let mut smoothed = Smoothed::new([0.0]);
+        smoothed.set_target([1.0], 4);
+        smoothed.advance(2);
+        smoothed.set_target([0.0], 2);
+        let ramp = smoothed.advance(1);
+        assert_eq!(ramp.start, [0.5]);
+        assert_eq!(ramp.end, [0.25]);
+    }
+
+    #[test]
+    fn no_smoothing_jumps_to_target() {
+        let mut smoothed = Smoothed::new([0.0]);
+        smoothed.set_target([1.0], 0);
+        let ramp = smoothed.advance(64);
+        assert_eq!(ramp.start, [1.0]);
+        assert_eq!(ramp.end, [1.0]);
+    }
+
+    #[test]
+    fn ramp_scale() {
+        let ramp = Ramp {
+            start: [1.0, 2.0],
+            end: [3.0, 4.0],
+            samples: 8,
+        }
+        .scale(0.5);
+        assert_eq!(ramp.start, [0.5, 1.0]);
+        assert_eq!(ramp.end, [1.5, 2.0]);
+        assert_eq!(ramp.samples, 8);
+        assert_eq!(Ramp::constant([0.25]).end, [0.25]);
+    }
+}
@@ -1,16 +1,99 @@
+use crate::atom_output::{self, AtomOutputEvent};
+use crate::audio_clip::{AudioClip, AudioClipRegion};
 use crate::channels::FixedChannels;
+use crate::meter::{Meter, MeterState};
+use crate::midi::{MidiInput, MidiOutputBuffer};
+use crate::pan::PanLaw;
+use crate::sequencer::Sequencer;
+use crate::smooth::{Ramp, Smoothed};
+use crate::transport::{TimeUrids, TransportCycle, TIME_POSITION_SIZE};
 use crate::{Id, RawMidi};
-use livi::event::LV2AtomSequence;
+use livi::event::{LV2AtomEventBuilder, LV2AtomSequence};
 use log::error;
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TrackProperty {
     Gain,
+    /// The pan position from -1.0 (left) to 1.0 (right).
+    Pan,
+    /// 1.0 if the track is muted and 0.0 otherwise.
+    Mute,
+    /// 1.0 if the track is soloed and 0.0 otherwise.
+    Solo,
+    /// 1.0 if the midi output of the track is sent to the backend and 0.0
+    /// otherwise.
+    MidiOutput,
 }
 
-struct InstanceContainer {
+/// The backend audio channels that feed into a track.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum InputSource {
+    #[default]
+    None,
+    /// A single channel that is fed into both the left and right channels.
+    Mono(usize),
+    /// A left and right channel.
+    Stereo(usize, usize),
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AudioInput {
+    pub source: InputSource,
+    /// If false, the source is not fed into the track.
+    pub monitoring: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum InstanceProperty {
+    /// 1.0 if the instance should pass its input through unchanged and 0.0
+    /// otherwise.
+    Bypass,
+    /// The amount of processed signal from 0.0 (dry) to 1.0 (wet).
+    Mix,
+}
+
+/// The maximum number of plugin instances on a single track.
+pub const MAX_PLUGIN_INSTANCES: usize = 64;
+
+/// The maximum number of sends on a single track.
+pub const MAX_SENDS: usize = 16;
+
+/// The maximum number of audio clips on a single track.
+pub const MAX_AUDIO_CLIPS: usize = 64;
+
+/// Sends a portion of a track's output to the input of another track.
+#[derive(Copy, Clone, Debug)]
+pub struct AuxSend {
+    pub id: Id,
+    /// The id of the track that receives the signal.
+    pub destination: Id,
+    pub level: f32,
+    /// If true, the signal is taken before the track's gain and pan.
+    pub pre_fader: bool,
+}
+
+/// A plugin instance along with how it is mixed into its track.
+pub struct InstanceContainer {
     id: Id,
     instance: Box<livi::Instance>,
+    bypassed: bool,
+    mix: f32,
+}
+
+impl InstanceContainer {
+    fn new(id: Id, instance: Box<livi::Instance>) -> InstanceContainer {
+        InstanceContainer {
+            id,
+            instance,
+            bypassed: false,
+            mix: 1.0,
+        }
+    }
+
+    pub fn into_instance(self) -> Box<livi::Instance> {
+        self.instance
+    }
 }
 
 pub struct Track {
@@ -20,8 +103,20 @@ pub struct Track {
     atom_input: LV2AtomSequence,
     atom_output: LV2AtomSequence,
     midi_urid: lv2_raw::LV2Urid,
+    time_urids: TimeUrids,
     gain: f32,
+    pan: f32,
+    mute: bool,
+    solo: bool,
+    midi_output: bool,
+    fader: Smoothed<2>,
+    midi_input: MidiInput,
+    audio_input: AudioInput,
     instances: Vec<InstanceContainer>,
+    sends: Vec<AuxSend>,
+    sequencer: Sequencer,
+    audio_clips: Vec<AudioClip>,
+    meter: MeterState,
 }
 
 impl Track {
@@ -34,21 +129,213 @@ impl Track {
             atom_input: LV2AtomSequence::new(features, LV2_ATOM_SEQUENCE_SIZE),
             atom_output: LV2AtomSequence::new(features, LV2_ATOM_SEQUENCE_SIZE),
             midi_urid: features.midi_urid(),
+            time_urids: TimeUrids::new(features),
             gain: 1.0,
-            instances: Vec::with_capacity(64),
+            pan: 0.0,
+            mute: false,
+            solo: false,
+            midi_output: false,
+            fader: Smoothed::new([0.0; 2]),
+            midi_input: MidiInput::default(),
+            audio_input: AudioInput::default(),
+            instances: Vec::with_capacity(MAX_PLUGIN_INSTANCES),
+            sends: Vec::with_capacity(MAX_SENDS),
+            sequencer: Sequencer::default(),
+            audio_clips: Vec::with_capacity(MAX_AUDIO_CLIPS),
+            meter: MeterState::default(),
         }
     }
 
-    pub fn push_instance(&mut self, id: Id, instance: Box<livi::Instance>) {
-        self.instances.push(InstanceContainer { id, instance });
+    /// Add a plugin instance after the existing ones. If the track already has
+    /// `MAX_PLUGIN_INSTANCES` instances, the instance is returned.
+    pub fn push_instance(
+        &mut self,
+        id: Id,
+        instance: Box<livi::Instance>,
+    ) -> Result<(), Box<livi::Instance>> {
+        if self.instances.len() >= MAX_PLUGIN_INSTANCES {
+            return Err(instance);
+        }
+        self.instances.push(InstanceContainer::new(id, instance));
+        Ok(())
     }
 
-    pub fn delete_instance(&mut self, id: Id) -> Option<Box<livi::Instance>> {
+    /// Move the plugin instance with `id` to `index` within this track. If
+    /// `index` is past the end, then the instance is placed last. Returns
+    /// `true` if the instance was found on this track.
+    pub fn move_instance(&mut self, id: Id, index: usize) -> bool {
+        let idx = match self.instances.iter().position(|instance| instance.id == id) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let container = self.instances.remove(idx);
+        let index = index.min(self.instances.len());
+        self.instances.insert(index, container);
+        true
+    }
+
+    /// Remove the plugin instance with `id` while keeping its bypass and mix
+    /// so that it can be inserted into another track.
+    pub fn take_instance(&mut self, id: Id) -> Option<InstanceContainer> {
         let idx = self
             .instances
             .iter()
             .position(|instance| instance.id == id)?;
-        Some(self.instances.remove(idx).instance)
+        Some(self.instances.remove(idx))
+    }
+
+    /// Insert a plugin instance that was taken from another track at `index`.
+    /// If `index` is past the end, then the instance is placed last. If the
+    /// track has no room for it, the instance is returned.
+    pub fn insert_taken_instance(
+        &mut self,
+        index: usize,
+        container: InstanceContainer,
+    ) -> Result<(), InstanceContainer> {
+        if self.instances.len() >= MAX_PLUGIN_INSTANCES {
+            return Err(container);
+        }
+        let index = index.min(self.instances.len());
+        self.instances.insert(index, container);
+        Ok(())
+    }
+
+    pub fn delete_instance(&mut self, id: Id) -> Option<Box<livi::Instance>> {
+        self.take_instance(id).map(InstanceContainer::into_instance)
+    }
+
+    /// Set the value of the control input `port` on the plugin instance with
+    /// `id`. Returns `true` if the instance was found on this track.
+    pub fn set_instance_control(&mut self, id: Id, port: livi::PortIndex, value: f32) -> bool {
+        match self.instances.iter_mut().find(|instance| instance.id == id) {
+            Some(instance_container) => {
+                if instance_container
+                    .instance
+                    .set_control_input(port, value)
+                    .is_none()
+                {
+                    error!(
+                        "Port {:?} is not a control input for instance {}.",
+                        port, id
+                    );
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set a property on the plugin instance with `id`. Returns `true` if the
+    /// instance was found on this track.
+    pub fn set_instance_property(
+        &mut self,
+        id: Id,
+        property: InstanceProperty,
+        value: f32,
+    ) -> bool {
+        match self.instances.iter_mut().find(|instance| instance.id == id) {
+            Some(instance_container) => {
+                match property {
+                    InstanceProperty::Bypass => instance_container.bypassed = value > 0.5,
+                    InstanceProperty::Mix => instance_container.mix = value.clamp(0.0, 1.0),
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn sends(&self) -> &[AuxSend] {
+        &self.sends
+    }
+
+    /// Add `send` to the track. Returns `false` if the track already has
+    /// `MAX_SENDS` sends.
+    pub fn push_send(&mut self, send: AuxSend) -> bool {
+        if self.sends.len() >= MAX_SENDS {
+            return false;
+        }
+        self.sends.push(send);
+        true
+    }
+
+    /// Update the send with `id`. Returns `true` if the send was found on this
+    /// track.
+    pub fn update_send(&mut self, id: Id, level: f32, pre_fader: bool) -> bool {
+        match self.sends.iter_mut().find(|s| s.id == id) {
+            Some(send) => {
+                send.level = level;
+                send.pre_fader = pre_fader;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Delete the send with `id`. Returns `true` if the send was found on this
+    /// track.
+    pub fn delete_send(&mut self, id: Id) -> bool {
+        let len = self.sends.len();
+        self.sends.retain(|s| s.id != id);
+        len != self.sends.len()
+    }
+
+    /// Delete all sends that feed into the track with `destination`.
+    pub fn delete_sends_to(&mut self, destination: Id) {
+        self.sends.retain(|s| s.destination != destination);
+    }
+
+    pub fn set_audio_input(&mut self, audio_input: AudioInput) {
+        self.audio_input = audio_input;
+    }
+
+    pub fn audio_input(&self) -> AudioInput {
+        self.audio_input
+    }
+
+    /// Mix the channels of `audio_in` selected by the track's audio input into
+    /// the input of the track.
+    pub fn mix_audio_input(&mut self, audio_in: &[&[f32]]) {
+        if !self.audio_input.monitoring {
+            return;
+        }
+        let channels = match self.audio_input.source {
+            InputSource::None => return,
+            InputSource::Mono(channel) => [channel, channel],
+            InputSource::Stereo(left, right) => [left, right],
+        };
+        for (dst, channel) in self.input.iter_channels_mut().zip(channels.iter()) {
+            if let Some(src) = audio_in.get(*channel) {
+                for (x, y) in dst.iter_mut().zip(src.iter()) {
+                    *x += *y;
+                }
+            }
+        }
+    }
+
+    pub fn clear_input(&mut self) {
+        self.input.clear();
+    }
+
+    /// Mix `audio` into the input of the track. The input is passed to the
+    /// first plugin on the next call to `process`.
+    pub fn mix_input(&mut self, audio: &FixedChannels<2>, ramp: &Ramp<2>) {
+        self.input.mix_ramped(audio, ramp);
+    }
+
+    /// The output from the last call to `process`.
+    pub fn output(&self) -> &FixedChannels<2> {
+        &self.output
+    }
+
+    /// The meter that measures the track's output after its fader.
+    pub fn meter(&self) -> Arc<Meter> {
+        self.meter.meter()
+    }
+
+    /// Measure the first `samples` of the output with `fader` applied.
+    pub fn measure_output(&mut self, fader: &Ramp<2>, samples: usize) {
+        self.meter.measure(&self.output, fader, samples);
     }
 
     pub fn set_buffer_size(&mut self, buffer_size: usize) {
@@ -59,32 +346,158 @@ impl Track {
     pub fn set_property(&mut self, property: TrackProperty, value: f32) {
         match property {
             TrackProperty::Gain => self.gain = value,
+            TrackProperty::Pan => self.pan = value.clamp(-1.0, 1.0),
+            TrackProperty::Mute => self.mute = value > 0.5,
+            TrackProperty::Solo => self.solo = value > 0.5,
+            TrackProperty::MidiOutput => self.midi_output = value > 0.5,
         }
     }
 
     pub fn property(&self, property: TrackProperty) -> f32 {
+        let bool_to_f32 = |b| if b { 1.0 } else { 0.0 };
         match property {
             TrackProperty::Gain => self.gain,
+            TrackProperty::Pan => self.pan,
+            TrackProperty::Mute => bool_to_f32(self.mute),
+            TrackProperty::Solo => bool_to_f32(self.solo),
+            TrackProperty::MidiOutput => bool_to_f32(self.midi_output),
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.mute
+    }
+
+    pub fn is_soloed(&self) -> bool {
+        self.solo
+    }
+
+    /// The left and right gain of the track after applying `pan_law`.
+    pub fn output_gains(&self, pan_law: PanLaw) -> [f32; 2] {
+        let [left, right] = pan_law.gains(self.pan);
+        [left * self.gain, right * self.gain]
+    }
+
+    /// Move the output gains of the track towards `target` and return the ramp
+    /// to apply to the next `samples` of output.
+    pub fn advance_fader(
+        &mut self,
+        target: [f32; 2],
+        smoothing_samples: usize,
+        samples: usize,
+    ) -> Ramp<2> {
+        self.fader.set_target(target, smoothing_samples);
+        self.fader.advance(samples)
+    }
+
+    pub fn set_midi_input(&mut self, midi_input: MidiInput) {
+        self.midi_input = midi_input;
+    }
+
+    pub fn midi_input(&self) -> MidiInput {
+        self.midi_input
+    }
+
+    pub fn sequencer_mut(&mut self) -> &mut Sequencer {
+        &mut self.sequencer
+    }
+
+    /// Add `clip` to the track. If the track already has `MAX_AUDIO_CLIPS`
+    /// clips, the clip is returned.
+    pub fn push_audio_clip(&mut self, clip: AudioClip) -> Result<(), AudioClip> {
+        if self.audio_clips.len() >= MAX_AUDIO_CLIPS {
+            return Err(clip);
         }
+        self.audio_clips.push(clip);
+        Ok(())
     }
 
-    pub fn process<'a, M>(&mut self, samples: usize, midi_input: M) -> &FixedChannels<2>
+    /// Returns true if the clip was found.
+    pub fn set_audio_clip_region(&mut self, id: Id, region: AudioClipRegion) -> bool {
+        match self.audio_clips.iter_mut().find(|c| c.id() == id) {
+            Some(clip) => {
+                clip.set_region(region);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn delete_audio_clip(&mut self, id: Id) -> Option<AudioClip> {
+        let idx = self.audio_clips.iter().position(|c| c.id() == id)?;
+        Some(self.audio_clips.remove(idx))
+    }
+
+    /// Process the next `samples` of audio. If the transport has a position,
+    /// then it is sent to all plugins before any midi. Midi from the track's
+    /// clips is merged with `midi_input` and audio clips are mixed into the
+    /// input. If `atom_output_queue` is set, then the atom output of each
+    /// plugin instance is copied to it.
+    pub fn process<'a, M>(
+        &mut self,
+        samples: usize,
+        midi_input: M,
+        transport: &TransportCycle,
+        mut atom_output_queue: Option<&mut ringbuf::Producer<AtomOutputEvent>>,
+    ) -> &FixedChannels<2>
     where
         M: Iterator<Item = RawMidi<'a>>,
     {
+        for clip in self.audio_clips.iter_mut() {
+            clip.mix_into(&mut self.input, transport, samples);
+        }
+        // The signal in `output` is swapped into `input` before each plugin
+        // runs. If there are no plugins, the input is passed through as is.
+        std::mem::swap(&mut self.input, &mut self.output);
         self.input.clear();
-        self.output.clear();
         self.atom_input.clear();
-        for message in midi_input {
-            if let Err(e) = self.atom_input.push_midi_event::<3>(
-                message.frame as i64,
+        if let Some(position) = transport.position.as_ref() {
+            let (data, size) = self.time_urids.encode(position);
+            match LV2AtomEventBuilder::<TIME_POSITION_SIZE>::new(
+                0,
+                self.time_urids.object,
+                &data[..size],
+            ) {
+                Ok(event) => {
+                    if let Err(e) = self.atom_input.push_event(&event) {
+                        error!("{:?}", e);
+                    }
+                }
+                Err(e) => error!("{:?}", e),
+            }
+        }
+        self.sequencer.render(transport, samples);
+        let mut sequencer_events = self.sequencer.events().iter().peekable();
+        let track_midi_input = self.midi_input;
+        for message in midi_input.filter(|m| track_midi_input.accepts(m)) {
+            while let Some(event) = sequencer_events.next_if(|e| e.frame <= message.frame) {
+                push_midi(
+                    &mut self.atom_input,
+                    self.midi_urid,
+                    event.frame,
+                    &event.data,
+                );
+            }
+            push_midi(
+                &mut self.atom_input,
                 self.midi_urid,
+                message.frame,
                 message.data,
-            ) {
-                error!("{:?}", e);
-            };
+            );
+        }
+        for event in sequencer_events {
+            push_midi(
+                &mut self.atom_input,
+                self.midi_urid,
+                event.frame,
+                &event.data,
+            );
         }
         for instance_container in self.instances.iter_mut() {
+            // Leaving the signal in `output` passes it on to the next plugin.
+            if instance_container.bypassed {
+                continue;
+            }
             std::mem::swap(&mut self.input, &mut self.output);
             let ports = livi::EmptyPortConnections::new()
                 .with_audio_inputs(
@@ -123,11 +536,17 @@ impl Track {
             if let Err(e) = unsafe { instance_container.instance.run(samples, ports) } {
                 error!("Failed to run plugin: {:?}", e);
             };
+            if instance_container.mix < 1.0 {
+                self.output.blend(&self.input, instance_container.mix);
+            }
             if instance_container
                 .instance
                 .port_counts_for_type(livi::PortType::AtomSequenceOutput)
                 > 0
             {
+                if let Some(queue) = atom_output_queue.as_deref_mut() {
+                    atom_output::forward(instance_container.id, &self.atom_output, queue);
+                }
                 std::mem::swap(&mut self.atom_input, &mut self.atom_output);
             }
         }
@@ -137,4 +556,34 @@ impl Track {
     pub fn id(&self) -> Id {
         self.id
     }
+
+    /// Push the midi events from the last processing cycle to `buffer` if
+    /// midi output is enabled. This is the midi output of the last plugin
+    /// with an atom output or, if there is no such plugin, the midi that was
+    /// fed into the track.
+    pub fn write_midi_output(&self, buffer: &mut MidiOutputBuffer) {
+        if !self.midi_output {
+            return;
+        }
+        for event in self.atom_input.iter() {
+            if event.event.body.mytype == self.midi_urid {
+                buffer.push(
+                    self.id,
+                    event.event.time_in_frames.max(0) as usize,
+                    event.data,
+                );
+            }
+        }
+    }
+}
+
+fn push_midi(
+    sequence: &mut LV2AtomSequence,
+    midi_urid: lv2_raw::LV2Urid,
+    frame: usize,
+    data: &[u8],
+) {
+    if let Err(e) = sequence.push_midi_event::<3>(frame as i64, midi_urid, data) {
+        error!("{:?}", e);
+    }
 }
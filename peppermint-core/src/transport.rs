@@ -0,0 +1,225 @@
+use std::ffi::CStr;
+
+/// The tempo and time signature.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tempo {
+    pub beats_per_minute: f64,
+    pub beats_per_bar: u32,
+    /// The note value that counts as one beat. For example, 4 for quarter
+    /// notes.
+    pub beat_unit: u32,
+}
+
+impl Default for Tempo {
+    fn default() -> Tempo {
+        Tempo {
+            beats_per_minute: 120.0,
+            beats_per_bar: 4,
+            beat_unit: 4,
+        }
+    }
+}
+
+/// Keeps track of the playhead.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Transport {
+    playing: bool,
+    frame: u64,
+    beat: f64,
+    tempo: Tempo,
+}
+
+/// A snapshot of the transport at a single frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimePosition {
+    pub frame: u64,
+    /// 1.0 if the transport is playing and 0.0 if it is stopped.
+    pub speed: f32,
+    pub bar: i64,
+    /// The beat within the current bar.
+    pub bar_beat: f32,
+    pub tempo: Tempo,
+}
+
+/// The state of the transport for a single call to `process`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TransportCycle {
+    /// Set if the position should be sent to all plugins.
+    pub position: Option<TimePosition>,
+    pub playing: bool,
+    /// True if the transport stopped or the playhead jumped since the last
+    /// cycle.
+    pub jumped: bool,
+    /// The frame at the start of the cycle.
+    pub frame: u64,
+    /// The beat at the start of the cycle.
+    pub beat: f64,
+    pub beats_per_frame: f64,
+}
+
+impl Transport {
+    pub fn play(&mut self) {
+        self.playing = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Move the playhead to `frame`.
+    pub fn seek(&mut self, frame: u64, sample_rate: f64) {
+        self.frame = frame;
+        self.beat = frame as f64 * beats_per_frame(&self.tempo, sample_rate);
+    }
+
+    /// Set the tempo. The playhead keeps its position in beats.
+    pub fn set_tempo(&mut self, tempo: Tempo) {
+        self.tempo = tempo;
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The position of the playhead in beats since the start.
+    pub fn beat(&self) -> f64 {
+        self.beat
+    }
+
+    pub fn tempo(&self) -> Tempo {
+        self.tempo
+    }
+
+    /// The number of beats that pass for each frame.
+    pub fn beats_per_frame(&self, sample_rate: f64) -> f64 {
+        beats_per_frame(&self.tempo, sample_rate)
+    }
+
+    /// Move the playhead forward by `samples` if the transport is playing.
+    pub fn advance(&mut self, samples: usize, sample_rate: f64) {
+        if self.playing {
+            self.frame += samples as u64;
+            self.beat += samples as f64 * beats_per_frame(&self.tempo, sample_rate);
+        }
+    }
+
+    pub fn position(&self) -> TimePosition {
+        let beats_per_bar = self.tempo.beats_per_bar.max(1) as f64;
+        let bar = (self.beat / beats_per_bar).floor();
+        TimePosition {
+            frame: self.frame,
+            speed: if self.playing { 1.0 } else { 0.0 },
+            bar: bar as i64,
+            bar_beat: (self.beat - bar * beats_per_bar) as f32,
+            tempo: self.tempo,
+        }
+    }
+}
+
+fn beats_per_frame(tempo: &Tempo, sample_rate: f64) -> f64 {
+    tempo.beats_per_minute / (60.0 * sample_rate)
+}
+
+/// The maximum size of an encoded `TimePosition` object.
+pub const TIME_POSITION_SIZE: usize = 256;
+
+/// The URIDs required to encode a `TimePosition` as an LV2 `time:Position`
+/// atom object.
+#[derive(Copy, Clone, Debug)]
+pub struct TimeUrids {
+    pub object: lv2_raw::LV2Urid,
+    int: lv2_raw::LV2Urid,
+    long: lv2_raw::LV2Urid,
+    float: lv2_raw::LV2Urid,
+    position: lv2_raw::LV2Urid,
+    frame: lv2_raw::LV2Urid,
+    speed: lv2_raw::LV2Urid,
+    bar: lv2_raw::LV2Urid,
+    bar_beat: lv2_raw::LV2Urid,
+    beat_unit: lv2_raw::LV2Urid,
+    beats_per_bar: lv2_raw::LV2Urid,
+    beats_per_minute: lv2_raw::LV2Urid,
+}
+
+impl TimeUrids {
+    pub fn new(features: &livi::Features) -> TimeUrids {
+        let urid = |uri: &[u8]| features.urid(CStr::from_bytes_with_nul(uri).unwrap());
+        TimeUrids {
+            object: urid(lv2_raw::LV2_ATOM__OBJECT),
+            int: urid(lv2_raw::LV2_ATOM__INT),
+            long: urid(lv2_raw::LV2_ATOM__LONG),
+            float: urid(lv2_raw::LV2_ATOM__FLOAT),
+            position: urid(lv2_raw::LV2_TIME__POSITION),
+            frame: urid(lv2_raw::LV2_TIME__FRAME),
+            speed: urid(lv2_raw::LV2_TIME__SPEED),
+            bar: urid(lv2_raw::LV2_TIME__BAR),
+            bar_beat: urid(lv2_raw::LV2_TIME__BARBEAT),
+            beat_unit: urid(lv2_raw::LV2_TIME__BEATUNIT),
+            beats_per_bar: urid(lv2_raw::LV2_TIME__BEATSPERBAR),
+            beats_per_minute: urid(lv2_raw::LV2_TIME__BEATSPERMINUTE),
+        }
+    }
+
+    /// Encode `position` as the body of a `time:Position` atom object. The
+    /// size of the body is returned alongside it.
+    pub fn encode(&self, position: &TimePosition) -> ([u8; TIME_POSITION_SIZE], usize) {
+        let mut writer = AtomObjectWriter {
+            buffer: [0; TIME_POSITION_SIZE],
+            size: 0,
+        };
+        // The object id (0 for blank) followed by the object type.
+        writer.write(&0u32.to_ne_bytes());
+        writer.write(&self.position.to_ne_bytes());
+        writer.property(
+            self.frame,
+            self.long,
+            &(position.frame as i64).to_ne_bytes(),
+        );
+        writer.property(self.speed, self.float, &position.speed.to_ne_bytes());
+        writer.property(self.bar, self.long, &position.bar.to_ne_bytes());
+        writer.property(self.bar_beat, self.float, &position.bar_beat.to_ne_bytes());
+        writer.property(
+            self.beat_unit,
+            self.int,
+            &(position.tempo.beat_unit as i32).to_ne_bytes(),
+        );
+        writer.property(
+            self.beats_per_bar,
+            self.float,
+            &(position.tempo.beats_per_bar as f32).to_ne_bytes(),
+        );
+        writer.property(
+            self.beats_per_minute,
+            self.float,
+            &(position.tempo.beats_per_minute as f32).to_ne_bytes(),
+        );
+        (writer.buffer, writer.size)
+    }
+}
+
+struct AtomObjectWriter {
+    buffer: [u8; TIME_POSITION_SIZE],
+    size: usize,
+}
+
+impl AtomObjectWriter {
+    fn write(&mut self, data: &[u8]) {
+        self.buffer[self.size..self.size + data.len()].copy_from_slice(data);
+        self.size += data.len();
+    }
+
+    /// Write an `LV2_Atom_Property_Body`. The value is padded to 64 bits.
+    fn property(&mut self, key: lv2_raw::LV2Urid, value_type: lv2_raw::LV2Urid, value: &[u8]) {
+        self.write(&key.to_ne_bytes());
+        // The context is unused.
+        self.write(&0u32.to_ne_bytes());
+        self.write(&(value.len() as u32).to_ne_bytes());
+        self.write(&value_type.to_ne_bytes());
+        self.write(value);
+        self.size = (self.size + 7) & !7;
+    }
+}
@@ -0,0 +1,89 @@
+use crate::manager::Lv2Features;
+use log::{debug, warn};
+use peppermint_core::atom_output::AtomOutputEvent;
+use ringbuf::Consumer;
+use std::{collections::HashSet, time::Duration};
+use tokio::sync::{broadcast, mpsc};
+
+/// Moves atom output events from the audio thread to `events`. This runs
+/// forever and should be run on a thread that is not realtime.
+pub fn run(
+    queue: Consumer<AtomOutputEvent>,
+    features: Lv2Features,
+    events: broadcast::Sender<peppermint_proto::AtomOutputEvent>,
+    interval: Duration,
+) {
+    let mut queue = queue;
+    let midi_urid = features.0.midi_urid();
+    loop {
+        std::thread::sleep(interval);
+        queue.pop_each(
+            |event| {
+                // Sending only fails if there are no clients.
+                let _ = events.send(event_to_proto(&event, &features, midi_urid));
+                true
+            },
+            None,
+        );
+    }
+}
+
+/// Send the events from `plugin_instance_ids` to `updates` until `updates`
+/// is closed. If `plugin_instance_ids` is empty, then all events are sent.
+pub async fn watch(
+    mut events: broadcast::Receiver<peppermint_proto::AtomOutputEvent>,
+    plugin_instance_ids: HashSet<peppermint_core::Id>,
+    updates: mpsc::Sender<Result<peppermint_proto::AtomOutputEvent, tonic::Status>>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if !plugin_instance_ids.is_empty()
+                    && !plugin_instance_ids.contains(&event.plugin_instance_id)
+                {
+                    continue;
+                }
+                if updates.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                warn!(
+                    "Client fell behind and missed {} atom output events.",
+                    count
+                );
+            }
+            Err(broadcast::error::RecvError::Closed) => {
+                debug!("Atom output events are no longer produced.");
+                return;
+            }
+        }
+    }
+}
+
+fn event_to_proto(
+    event: &AtomOutputEvent,
+    features: &Lv2Features,
+    midi_urid: u32,
+) -> peppermint_proto::AtomOutputEvent {
+    let mut proto = peppermint_proto::AtomOutputEvent {
+        plugin_instance_id: event.instance,
+        frame: event.frame,
+        midi: Vec::new(),
+        atom: None,
+    };
+    if event.type_urid == midi_urid {
+        proto.midi = event.data().to_vec();
+    } else {
+        proto.atom = Some(peppermint_proto::Atom {
+            type_uri: features
+                .0
+                .uri(event.type_urid)
+                .unwrap_or_default()
+                .to_string(),
+            data: event.data().to_vec(),
+            truncated: event.is_truncated(),
+        });
+    }
+    proto
+}
@@ -9,8 +9,10 @@ pub fn run(peppermint: peppermint_core::PeppermintCore, buffer_size: usize) {
         // Add a delay to decrease the CPU usage.
         std::thread::sleep(std::time::Duration::from_millis(20));
         let io = peppermint_core::IO {
+            audio_in: &[],
             audio_out: &mut out,
             midi: std::iter::empty(),
+            midi_out: None,
         };
         peppermint.process(io, buffer_size);
     }
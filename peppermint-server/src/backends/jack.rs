@@ -1,15 +1,44 @@
-use log::info;
+use log::{info, warn};
+
+/// The maximum number of audio inputs that may be registered.
+const MAX_AUDIO_INPUTS: usize = 32;
+
+/// The maximum number of midi events that are output in a single cycle.
+const MAX_MIDI_OUTPUT_EVENTS: usize = 1024;
 
 pub fn sample_rate_and_buffer_size() -> Result<(f64, usize), jack::Error> {
     let (client, _) = jack::Client::new("peppermint_probe", jack::ClientOptions::NO_START_SERVER)?;
     Ok((client.sample_rate() as f64, client.buffer_size() as usize))
 }
 
-pub fn run(peppermint: peppermint_core::PeppermintCore) -> Result<(), jack::Error> {
+pub fn run(
+    peppermint: peppermint_core::PeppermintCore,
+    midi_inputs: usize,
+    audio_inputs: usize,
+) -> Result<(), jack::Error> {
     let (client, status) = jack::Client::new("peppermint", jack::ClientOptions::NO_START_SERVER)?;
     info!("Started client {} with status {:?}.", client.name(), status);
+    if audio_inputs > MAX_AUDIO_INPUTS {
+        warn!(
+            "Requested {} audio inputs but only {} are supported.",
+            audio_inputs, MAX_AUDIO_INPUTS
+        );
+    }
+    let audio_inputs = audio_inputs.min(MAX_AUDIO_INPUTS);
     let processor = Processor {
-        midi_in: client.register_port("midi_in", jack::MidiIn::default())?,
+        inputs: (0..audio_inputs)
+            .map(|idx| client.register_port(&format!("in_{}", idx + 1), jack::AudioIn::default()))
+            .collect::<Result<_, _>>()?,
+        midi_inputs: (0..midi_inputs)
+            .map(|idx| match idx {
+                0 => client.register_port("midi_in", jack::MidiIn::default()),
+                idx => {
+                    client.register_port(&format!("midi_in_{}", idx + 1), jack::MidiIn::default())
+                }
+            })
+            .collect::<Result<_, _>>()?,
+        midi_output: client.register_port("midi_out", jack::MidiOut::default())?,
+        midi_out_buffer: peppermint_core::midi::MidiOutputBuffer::new(MAX_MIDI_OUTPUT_EVENTS),
         outputs: [
             client.register_port("out_left", jack::AudioOut::default())?,
             client.register_port("out_right", jack::AudioOut::default())?,
@@ -26,6 +55,15 @@ pub fn run(peppermint: peppermint_core::PeppermintCore) -> Result<(), jack::Erro
         .as_client()
         .connect_ports_by_name("peppermint:out_right", "system:playback_2")
         .ok();
+    for idx in 0..audio_inputs {
+        client
+            .as_client()
+            .connect_ports_by_name(
+                &format!("system:capture_{}", idx + 1),
+                &format!("peppermint:in_{}", idx + 1),
+            )
+            .ok();
+    }
     client
         .as_client()
         .connect_ports_by_name(
@@ -39,7 +77,10 @@ pub fn run(peppermint: peppermint_core::PeppermintCore) -> Result<(), jack::Erro
 }
 
 struct Processor {
-    midi_in: jack::Port<jack::MidiIn>,
+    midi_inputs: Vec<jack::Port<jack::MidiIn>>,
+    midi_output: jack::Port<jack::MidiOut>,
+    midi_out_buffer: peppermint_core::midi::MidiOutputBuffer,
+    inputs: Vec<jack::Port<jack::AudioIn>>,
     outputs: [jack::Port<jack::AudioOut>; 2],
     out_buffer: peppermint_core::channels::FixedChannels<2>,
     inner: peppermint_core::PeppermintCore,
@@ -47,14 +88,38 @@ struct Processor {
 
 impl jack::ProcessHandler for Processor {
     fn process(&mut self, _: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        let mut audio_in: [&[f32]; MAX_AUDIO_INPUTS] = [&[]; MAX_AUDIO_INPUTS];
+        for (dst, port) in audio_in.iter_mut().zip(self.inputs.iter()) {
+            *dst = port.as_slice(ps);
+        }
         let io = peppermint_core::IO {
+            audio_in: &audio_in[..self.inputs.len()],
             audio_out: &mut self.out_buffer,
-            midi: self.midi_in.iter(ps).map(|m| peppermint_core::RawMidi {
-                frame: m.time as usize,
-                data: m.bytes,
-            }),
+            midi: self
+                .midi_inputs
+                .iter()
+                .enumerate()
+                .flat_map(|(port, midi_in)| {
+                    midi_in.iter(ps).map(move |m| peppermint_core::RawMidi {
+                        port,
+                        frame: m.time as usize,
+                        data: m.bytes,
+                    })
+                }),
+            midi_out: Some(&mut self.midi_out_buffer),
         };
         self.inner.process(io, ps.n_frames() as usize);
+        let mut midi_writer = self.midi_output.writer(ps);
+        for event in self.midi_out_buffer.events() {
+            let message = jack::RawMidi {
+                time: event.frame as jack::Frames,
+                bytes: event.data(),
+            };
+            if let Err(e) = midi_writer.write(&message) {
+                warn!("Failed to write midi output: {:?}", e);
+                break;
+            }
+        }
         let srcs = self.out_buffer.iter_channels();
         let dsts = self.outputs.iter_mut();
         for (src, dst) in srcs.zip(dsts) {
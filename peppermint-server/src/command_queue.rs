@@ -0,0 +1,266 @@
+use peppermint_core::command::{Command, SequencedCommand};
+use ringbuf::{Consumer, Producer};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// The number of acknowledgements that may be queued by the audio thread. The
+/// audio thread sends at most one per process cycle.
+pub const ACK_QUEUE_SIZE: usize = 64;
+
+/// Sends commands to the audio thread and numbers them in the order they are
+/// sent.
+pub struct CommandQueue {
+    queue: Producer<SequencedCommand>,
+    last_sequence: u64,
+    /// The commands of the batch that is being built, if any.
+    batch: Option<Vec<Command>>,
+}
+
+impl CommandQueue {
+    pub fn new(queue: Producer<SequencedCommand>) -> CommandQueue {
+        CommandQueue {
+            queue,
+            last_sequence: 0,
+            batch: None,
+        }
+    }
+
+    /// Send `command` and return its sequence number. If the queue is full,
+    /// `command` is returned. If a batch is being built, `command` is added to
+    /// the batch instead and the sequence number it will have once the batch
+    /// is sent is returned.
+    pub fn push(&mut self, command: Command) -> Result<u64, Command> {
+        if let Some(batch) = self.batch.as_mut() {
+            batch.push(command);
+            // The batch starts with `Command::BeginBatch`.
+            return Ok(self.last_sequence + 1 + batch.len() as u64);
+        }
+        let sequence = self.last_sequence + 1;
+        self.queue
+            .push(SequencedCommand { sequence, command })
+            .map_err(|c| c.command)?;
+        self.last_sequence = sequence;
+        Ok(sequence)
+    }
+
+    /// The number of commands that can be sent before the queue is full.
+    pub fn remaining(&self) -> usize {
+        match self.batch.as_ref() {
+            Some(batch) => self.queue.remaining().saturating_sub(batch.len() + 1),
+            None => self.queue.remaining(),
+        }
+    }
+
+    /// Hold all commands that are pushed until `send_batch` or `discard_batch`
+    /// is called.
+    pub fn begin_batch(&mut self) {
+        self.batch = Some(Vec::new());
+    }
+
+    /// Send the commands that were pushed since `begin_batch`. The core applies
+    /// all of them in the same process cycle. If the queue does not have room
+    /// for all of them, none are sent and they are returned.
+    pub fn send_batch(&mut self) -> Result<(), Vec<Command>> {
+        let batch = match self.batch.take() {
+            Some(batch) if !batch.is_empty() => batch,
+            _ => return Ok(()),
+        };
+        if self.queue.remaining() < batch.len() + 1 {
+            return Err(batch);
+        }
+        let len = batch.len();
+        for command in std::iter::once(Command::BeginBatch { len }).chain(batch) {
+            // There is room for every command so this does not fail.
+            self.push(command).map_err(|_| Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// Drop the commands that were pushed since `begin_batch`.
+    pub fn discard_batch(&mut self) {
+        self.batch = None;
+    }
+
+    /// The sequence number of the last command that was sent or 0 if no
+    /// commands have been sent.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+}
+
+/// Publishes the sequence numbers that the audio thread acknowledges to
+/// `applied`. This runs forever and should be run on a thread that is not
+/// realtime.
+pub fn run(acks: Consumer<u64>, applied: watch::Sender<u64>, interval: Duration) {
+    let mut acks = acks;
+    loop {
+        std::thread::sleep(interval);
+        let mut last = None;
+        acks.pop_each(
+            |sequence| {
+                last = Some(sequence);
+                true
+            },
+            None,
+        );
+        if let Some(sequence) = last {
+            // Sending only fails if the manager has been dropped.
+            let _ = applied.send(sequence);
+        }
+    }
+}
+
+/// Wait until the command with `sequence` and all commands before it have been
+/// applied by the audio thread.
+pub async fn wait(
+    mut applied: watch::Receiver<u64>,
+    sequence: u64,
+    timeout: Duration,
+) -> Result<(), tonic::Status> {
+    let applied = async move {
+        while *applied.borrow() < sequence {
+            if applied.changed().await.is_err() {
+                return Err(tonic::Status::new(
+                    tonic::Code::Unavailable,
+                    "audio thread is no longer acknowledging commands",
+                ));
+            }
+        }
+        Ok(())
+    };
+    tokio::time::timeout(timeout, applied).await.map_err(|_| {
+        tonic::Status::new(
+            tonic::Code::DeadlineExceeded,
+            format!(
+                "command {} was not applied by the audio thread within {}ms",
+                sequence,
+                timeout.as_millis()
+            ),
+        )
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(capacity: usize) -> (CommandQueue, Consumer<SequencedCommand>) {
+        let (tx, rx) = ringbuf::RingBuffer::new(capacity).split();
+        (CommandQueue::new(tx), rx)
+    }
+
+    fn sequences(rx: &mut Consumer<SequencedCommand>) -> Vec<u64> {
+        std::iter::from_fn(|| rx.pop())
+            .map(|c| c.sequence)
+            .collect()
+    }
+
+    #[test]
+    fn commands_are_numbered_in_order() {
+        let (mut queue, mut rx) = queue(4);
+        assert_eq!(queue.last_sequence(), 0);
+        assert_eq!(queue.push(Command::Play).ok(), Some(1));
+        assert_eq!(queue.push(Command::Stop).ok(), Some(2));
+        assert_eq!(queue.last_sequence(), 2);
+        assert_eq!(queue.remaining(), 2);
+        assert_eq!(sequences(&mut rx), vec![1, 2]);
+    }
+
+    #[test]
+    fn full_queue_returns_command() {
+        let (mut queue, mut rx) = queue(1);
+        assert!(queue.push(Command::Play).is_ok());
+        assert!(matches!(queue.push(Command::Stop), Err(Command::Stop)));
+        assert_eq!(queue.last_sequence(), 1);
+        assert_eq!(sequences(&mut rx), vec![1]);
+    }
+
+    #[test]
+    fn batch_is_sent_after_begin_batch() {
+        let (mut queue, mut rx) = queue(8);
+        queue.push(Command::Play).ok();
+        queue.begin_batch();
+        assert_eq!(queue.push(Command::Stop).ok(), Some(3));
+        assert_eq!(queue.push(Command::Seek(0)).ok(), Some(4));
+        assert_eq!(queue.remaining(), 4);
+        assert_eq!(queue.last_sequence(), 1);
+        assert!(queue.send_batch().is_ok());
+        assert_eq!(queue.last_sequence(), 4);
+        let commands: Vec<_> = std::iter::from_fn(|| rx.pop()).collect();
+        assert_eq!(
+            commands.iter().map(|c| c.sequence).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+        assert!(matches!(
+            commands[1].command,
+            Command::BeginBatch { len: 2 }
+        ));
+        assert!(matches!(commands[2].command, Command::Stop));
+        assert!(matches!(commands[3].command, Command::Seek(0)));
+    }
+
+    #[test]
+    fn batch_that_does_not_fit_is_returned() {
+        let (mut queue, mut rx) = queue(2);
+        queue.begin_batch();
+        queue.push(Command::Play).ok();
+        queue.push(Command::Stop).ok();
+        assert_eq!(queue.send_batch().err().map(|b| b.len()), Some(2));
+        assert_eq!(queue.last_sequence(), 0);
+        assert_eq!(sequences(&mut rx), vec![]);
+        // The batch ended so commands are sent right away again.
+        assert_eq!(queue.push(Command::Play).ok(), Some(1));
+    }
+
+    #[test]
+    fn discarded_batch_is_not_sent() {
+        let (mut queue, mut rx) = queue(4);
+        queue.begin_batch();
+        queue.push(Command::Play).ok();
+        queue.discard_batch();
+        assert!(queue.send_batch().is_ok());
+        assert_eq!(queue.last_sequence(), 0);
+        assert_eq!(sequences(&mut rx), vec![]);
+    }
+
+    #[test]
+    fn empty_batch_sends_nothing() {
+        let (mut queue, mut rx) = queue(4);
+        queue.begin_batch();
+        assert!(queue.send_batch().is_ok());
+        assert_eq!(sequences(&mut rx), vec![]);
+    }
+
+    #[tokio::test]
+    async fn wait_returns_once_sequence_is_applied() {
+        let (applied_tx, applied_rx) = watch::channel(0);
+        tokio::spawn(async move {
+            for sequence in 1..=3 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                applied_tx.send(sequence).unwrap();
+            }
+            // Keep the sender alive so that `wait` does not see it closing.
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        assert!(wait(applied_rx, 2, Duration::from_secs(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn wait_times_out() {
+        let (_applied_tx, applied_rx) = watch::channel(1);
+        let err = wait(applied_rx, 2, Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::DeadlineExceeded);
+    }
+
+    #[tokio::test]
+    async fn wait_fails_if_audio_thread_stops_acknowledging() {
+        let (applied_tx, applied_rx) = watch::channel(1);
+        drop(applied_tx);
+        let err = wait(applied_rx, 2, Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+    }
+}
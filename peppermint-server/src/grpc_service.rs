@@ -1,18 +1,23 @@
-use std::sync::Mutex;
+use log::error;
+use std::sync::{Arc, Mutex, Weak};
 
-use crate::manager::PeppermintManager;
-use peppermint_core::command::Command;
-use ringbuf::Producer;
+use crate::{atom_forwarder, manager::PeppermintManager, meters, render, session_events};
 
 pub struct PeppermintServiceImpl {
-    inner: Mutex<PeppermintManager>,
+    inner: Arc<Mutex<PeppermintManager>>,
 }
 
 impl PeppermintServiceImpl {
-    pub fn new(sample_rate: f64, buffer_size: usize, commands: Producer<Command>) -> Self {
-        PeppermintServiceImpl {
-            inner: Mutex::new(PeppermintManager::new(sample_rate, buffer_size, commands)),
-        }
+    /// Create the service. The changes that midi bindings make on the audio
+    /// thread are copied into the session every `midi_sync_interval`. This
+    /// must be called from within a tokio runtime.
+    pub fn new(manager: PeppermintManager, midi_sync_interval: std::time::Duration) -> Self {
+        let inner = Arc::new(Mutex::new(manager));
+        tokio::spawn(sync_midi_bindings(
+            Arc::downgrade(&inner),
+            midi_sync_interval,
+        ));
+        PeppermintServiceImpl { inner }
     }
 
     fn lock_inner(&self) -> Result<std::sync::MutexGuard<PeppermintManager>, tonic::Status> {
@@ -20,10 +25,89 @@ impl PeppermintServiceImpl {
             .lock()
             .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))
     }
+
+    /// Run `f` on the manager. If `wait` is set, the result is returned once
+    /// the audio thread has applied the commands that were sent by `f`.
+    async fn call<T>(
+        &self,
+        wait: Option<std::time::Duration>,
+        f: impl FnOnce(&mut PeppermintManager) -> Result<T, tonic::Status>,
+    ) -> Result<T, tonic::Status> {
+        let (result, applied) = {
+            let mut inner = self.lock_inner()?;
+            let result = f(&mut inner)?;
+            (result, wait.map(|timeout| inner.wait_for_commands(timeout)))
+        };
+        if let Some(applied) = applied {
+            applied.await?;
+        }
+        Ok(result)
+    }
+}
+
+/// Sync the midi bindings of `manager` every `interval` until the service is
+/// dropped.
+async fn sync_midi_bindings(
+    manager: Weak<Mutex<PeppermintManager>>,
+    interval: std::time::Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let manager = match manager.upgrade() {
+            Some(manager) => manager,
+            None => return,
+        };
+        match manager.lock() {
+            Ok(mut manager) => manager.sync_midi_bindings(),
+            Err(e) => {
+                error!("Failed to sync midi bindings: {}", e);
+                return;
+            }
+        };
+    }
+}
+
+/// The request metadata that makes an RPC wait until its changes are applied
+/// by the audio thread. The value is the timeout in milliseconds.
+pub const WAIT_TIMEOUT_MS_KEY: &str = "wait-timeout-ms";
+
+/// The timeout in the `WAIT_TIMEOUT_MS_KEY` metadata of `req` or `None` if the
+/// RPC should not wait.
+fn wait_timeout<T>(req: &tonic::Request<T>) -> Result<Option<std::time::Duration>, tonic::Status> {
+    let value = match req.metadata().get(WAIT_TIMEOUT_MS_KEY) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let ms: u64 = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| {
+            tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("{} must be a number of milliseconds", WAIT_TIMEOUT_MS_KEY),
+            )
+        })?;
+    Ok(Some(std::time::Duration::from_millis(ms)))
 }
 
 #[tonic::async_trait]
 impl peppermint_proto::peppermint_server::Peppermint for PeppermintServiceImpl {
+    type RenderStream = tokio_stream::wrappers::ReceiverStream<
+        Result<peppermint_proto::RenderProgress, tonic::Status>,
+    >;
+    type WatchMetersStream = tokio_stream::wrappers::ReceiverStream<
+        Result<peppermint_proto::WatchMetersResponse, tonic::Status>,
+    >;
+    type WatchAtomOutputStream = tokio_stream::wrappers::ReceiverStream<
+        Result<peppermint_proto::AtomOutputEvent, tonic::Status>,
+    >;
+    type WatchSessionStream = tokio_stream::wrappers::ReceiverStream<
+        Result<peppermint_proto::SessionEvent, tonic::Status>,
+    >;
+
     async fn get_plugins(
         &self,
         _: tonic::Request<peppermint_proto::GetPluginsRequest>,
@@ -42,28 +126,36 @@ impl peppermint_proto::peppermint_server::Peppermint for PeppermintServiceImpl {
         &self,
         req: tonic::Request<peppermint_proto::CreateTrackRequest>,
     ) -> Result<tonic::Response<peppermint_proto::CreateTrackResponse>, tonic::Status> {
-        self.lock_inner()?.create_track(req)
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.create_track(req)))
+            .await
     }
 
     async fn delete_track(
         &self,
         req: tonic::Request<peppermint_proto::DeleteTrackRequest>,
     ) -> Result<tonic::Response<peppermint_proto::DeleteTrackResponse>, tonic::Status> {
-        self.lock_inner()?.delete_track(req)
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.delete_track(req)))
+            .await
     }
 
     async fn update_track(
         &self,
         req: tonic::Request<peppermint_proto::UpdateTrackRequest>,
     ) -> Result<tonic::Response<peppermint_proto::UpdateTrackResponse>, tonic::Status> {
-        self.lock_inner()?.update_track(req)
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.update_track(req)))
+            .await
     }
 
     async fn instantiate_plugin(
         &self,
         req: tonic::Request<peppermint_proto::InstantiatePluginRequest>,
     ) -> Result<tonic::Response<peppermint_proto::InstantiatePluginResponse>, tonic::Status> {
-        self.lock_inner()?.instantiate_plugin(req)
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.instantiate_plugin(req)))
+            .await
     }
 
     async fn delete_plugin_instance(
@@ -71,6 +163,280 @@ impl peppermint_proto::peppermint_server::Peppermint for PeppermintServiceImpl {
         req: tonic::Request<peppermint_proto::DeletePluginInstanceRequest>,
     ) -> Result<tonic::Response<peppermint_proto::DeletePluginInstanceResponse>, tonic::Status>
     {
-        self.lock_inner()?.delete_plugin_instance(req)
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.delete_plugin_instance(req)))
+            .await
+    }
+
+    async fn update_plugin_instance(
+        &self,
+        req: tonic::Request<peppermint_proto::UpdatePluginInstanceRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::UpdatePluginInstanceResponse>, tonic::Status>
+    {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.update_plugin_instance(req)))
+            .await
+    }
+
+    async fn set_pan_law(
+        &self,
+        req: tonic::Request<peppermint_proto::SetPanLawRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::SetPanLawResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.set_pan_law(req)))
+            .await
+    }
+
+    async fn create_send(
+        &self,
+        req: tonic::Request<peppermint_proto::CreateSendRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::CreateSendResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.create_send(req)))
+            .await
+    }
+
+    async fn update_send(
+        &self,
+        req: tonic::Request<peppermint_proto::UpdateSendRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::UpdateSendResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.update_send(req)))
+            .await
+    }
+
+    async fn delete_send(
+        &self,
+        req: tonic::Request<peppermint_proto::DeleteSendRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::DeleteSendResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.delete_send(req)))
+            .await
+    }
+
+    async fn move_plugin_instance(
+        &self,
+        req: tonic::Request<peppermint_proto::MovePluginInstanceRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::MovePluginInstanceResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.move_plugin_instance(req)))
+            .await
+    }
+
+    async fn play(
+        &self,
+        req: tonic::Request<peppermint_proto::PlayRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::PlayResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.play()).await
+    }
+
+    async fn stop(
+        &self,
+        req: tonic::Request<peppermint_proto::StopRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::StopResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.stop()).await
+    }
+
+    async fn seek(
+        &self,
+        req: tonic::Request<peppermint_proto::SeekRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::SeekResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.seek(req)).await
+    }
+
+    async fn set_tempo(
+        &self,
+        req: tonic::Request<peppermint_proto::SetTempoRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::SetTempoResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.set_tempo(req)))
+            .await
+    }
+
+    async fn create_midi_clip(
+        &self,
+        req: tonic::Request<peppermint_proto::CreateMidiClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::CreateMidiClipResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.create_midi_clip(req)))
+            .await
+    }
+
+    async fn update_midi_clip(
+        &self,
+        req: tonic::Request<peppermint_proto::UpdateMidiClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::UpdateMidiClipResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.update_midi_clip(req)))
+            .await
+    }
+
+    async fn delete_midi_clip(
+        &self,
+        req: tonic::Request<peppermint_proto::DeleteMidiClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::DeleteMidiClipResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.delete_midi_clip(req)))
+            .await
+    }
+
+    async fn create_audio_clip(
+        &self,
+        req: tonic::Request<peppermint_proto::CreateAudioClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::CreateAudioClipResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.create_audio_clip(req)))
+            .await
+    }
+
+    async fn update_audio_clip(
+        &self,
+        req: tonic::Request<peppermint_proto::UpdateAudioClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::UpdateAudioClipResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.update_audio_clip(req)))
+            .await
+    }
+
+    async fn delete_audio_clip(
+        &self,
+        req: tonic::Request<peppermint_proto::DeleteAudioClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::DeleteAudioClipResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.delete_audio_clip(req)))
+            .await
+    }
+
+    async fn start_recording(
+        &self,
+        req: tonic::Request<peppermint_proto::StartRecordingRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::StartRecordingResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.start_recording(req)).await
+    }
+
+    async fn stop_recording(
+        &self,
+        req: tonic::Request<peppermint_proto::StopRecordingRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::StopRecordingResponse>, tonic::Status> {
+        // Stopping always waits for the audio thread so that the file is
+        // complete when the call returns.
+        let stopped = self.lock_inner()?.stop_recording(req)?;
+        stopped.await
+    }
+
+    async fn render(
+        &self,
+        req: tonic::Request<peppermint_proto::RenderRequest>,
+    ) -> Result<tonic::Response<Self::RenderStream>, tonic::Status> {
+        let job = self.lock_inner()?.render(req)?;
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        std::thread::spawn(move || render::run(job, tx));
+        Ok(tonic::Response::new(
+            tokio_stream::wrappers::ReceiverStream::new(rx),
+        ))
+    }
+
+    async fn watch_meters(
+        &self,
+        req: tonic::Request<peppermint_proto::WatchMetersRequest>,
+    ) -> Result<tonic::Response<Self::WatchMetersStream>, tonic::Status> {
+        let interval = meters::interval_from_rate(req.get_ref().rate_hz)?;
+        let meters = self.lock_inner()?.meters();
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(meters::watch(meters, interval, tx));
+        Ok(tonic::Response::new(
+            tokio_stream::wrappers::ReceiverStream::new(rx),
+        ))
+    }
+    async fn watch_atom_output(
+        &self,
+        req: tonic::Request<peppermint_proto::WatchAtomOutputRequest>,
+    ) -> Result<tonic::Response<Self::WatchAtomOutputStream>, tonic::Status> {
+        let plugin_instance_ids = req.into_inner().plugin_instance_ids.into_iter().collect();
+        let events = self.lock_inner()?.subscribe_atom_output();
+        let (tx, rx) = tokio::sync::mpsc::channel(256);
+        tokio::spawn(atom_forwarder::watch(events, plugin_instance_ids, tx));
+        Ok(tonic::Response::new(
+            tokio_stream::wrappers::ReceiverStream::new(rx),
+        ))
+    }
+
+    async fn midi_learn(
+        &self,
+        req: tonic::Request<peppermint_proto::MidiLearnRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::MidiLearnResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.midi_learn(req)).await
+    }
+
+    async fn get_midi_bindings(
+        &self,
+        _: tonic::Request<peppermint_proto::GetMidiBindingsRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::GetMidiBindingsResponse>, tonic::Status> {
+        self.lock_inner()?.get_midi_bindings()
+    }
+
+    async fn delete_midi_binding(
+        &self,
+        req: tonic::Request<peppermint_proto::DeleteMidiBindingRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::DeleteMidiBindingResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.delete_midi_binding(req)).await
+    }
+
+    async fn save_project(
+        &self,
+        req: tonic::Request<peppermint_proto::SaveProjectRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::SaveProjectResponse>, tonic::Status> {
+        self.lock_inner()?.save_project(req)
+    }
+
+    async fn load_project(
+        &self,
+        req: tonic::Request<peppermint_proto::LoadProjectRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::LoadProjectResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.load_project(req)).await
+    }
+
+    async fn undo(
+        &self,
+        req: tonic::Request<peppermint_proto::UndoRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::UndoResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.undo()).await
+    }
+
+    async fn redo(
+        &self,
+        req: tonic::Request<peppermint_proto::RedoRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::RedoResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.redo()).await
+    }
+
+    async fn apply_batch(
+        &self,
+        req: tonic::Request<peppermint_proto::ApplyBatchRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::ApplyBatchResponse>, tonic::Status> {
+        let wait = wait_timeout(&req)?;
+        self.call(wait, |m| m.with_history(|m| m.apply_batch(req)))
+            .await
+    }
+
+    async fn watch_session(
+        &self,
+        _: tonic::Request<peppermint_proto::WatchSessionRequest>,
+    ) -> Result<tonic::Response<Self::WatchSessionStream>, tonic::Status> {
+        let events = self.lock_inner()?.subscribe_session();
+        let (tx, rx) = tokio::sync::mpsc::channel(256);
+        tokio::spawn(session_events::watch(events, tx));
+        Ok(tonic::Response::new(
+            tokio_stream::wrappers::ReceiverStream::new(rx),
+        ))
     }
 }
@@ -0,0 +1,170 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// The number of changes that can be undone.
+const HISTORY_SIZE: usize = 100;
+
+/// The changes that undo each change to a session and redo each undo.
+pub struct History<T> {
+    undo: VecDeque<T>,
+    redo: Vec<T>,
+}
+
+impl<T> History<T> {
+    pub fn new() -> History<T> {
+        History {
+            undo: VecDeque::with_capacity(HISTORY_SIZE),
+            redo: Vec::new(),
+        }
+    }
+
+    /// Record `change` as the change that undoes the latest change. Changes
+    /// that were undone can no longer be redone.
+    pub fn record(&mut self, change: T) {
+        self.push_undo(change);
+        self.redo.clear();
+    }
+
+    /// Take the change that undoes the last change.
+    pub fn pop_undo(&mut self) -> Option<T> {
+        self.undo.pop_back()
+    }
+
+    /// Take the change that redoes the last undone change.
+    pub fn pop_redo(&mut self) -> Option<T> {
+        self.redo.pop()
+    }
+
+    /// Push a change that can be applied by undo. The oldest change is dropped
+    /// if the history is full.
+    pub fn push_undo(&mut self, change: T) {
+        if self.undo.len() == HISTORY_SIZE {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(change);
+    }
+
+    /// Push a change that can be applied by redo.
+    pub fn push_redo(&mut self, change: T) {
+        self.redo.push(change);
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+impl<T> Default for History<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The entries that differ between `before` and `after` with their values in
+/// `before`, sorted by key. Entries that are only in `after` are `None`.
+/// Applying the result to `after` with `apply_entries` gives `before`.
+pub fn changed_entries<K, V>(before: &HashMap<K, V>, after: &HashMap<K, V>) -> Vec<(K, Option<V>)>
+where
+    K: Copy + Eq + Hash + Ord,
+    V: Clone + PartialEq,
+{
+    let mut keys: Vec<K> = before
+        .keys()
+        .chain(after.keys().filter(|k| !before.contains_key(k)))
+        .copied()
+        .collect();
+    keys.sort_unstable();
+    keys.into_iter()
+        .filter(|k| before.get(k) != after.get(k))
+        .map(|k| (k, before.get(&k).cloned()))
+        .collect()
+}
+
+/// Set the entries of `values` to `entries`. Entries that are `None` are
+/// removed.
+pub fn apply_entries<K, V>(
+    values: &mut HashMap<K, V>,
+    entries: impl IntoIterator<Item = (K, Option<V>)>,
+) where
+    K: Eq + Hash,
+{
+    for (key, value) in entries {
+        match value {
+            Some(value) => values.insert(key, value),
+            None => values.remove(&key),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: &[(u64, &'static str)]) -> HashMap<u64, &'static str> {
+        entries.iter().copied().collect()
+    }
+
+    #[test]
+    fn changed_entries_only_has_differences() {
+        let before = map(&[(1, "a"), (2, "b"), (3, "c")]);
+        let after = map(&[(1, "a"), (2, "B"), (4, "d")]);
+        assert_eq!(
+            changed_entries(&before, &after),
+            vec![(2, Some("b")), (3, Some("c")), (4, None)]
+        );
+        assert!(changed_entries(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn apply_entries_undoes_and_redoes() {
+        let before = map(&[(1, "a"), (2, "b"), (3, "c")]);
+        let after = map(&[(1, "a"), (2, "B"), (4, "d")]);
+        let undo = changed_entries(&before, &after);
+        let mut values = after.clone();
+        apply_entries(&mut values, undo);
+        assert_eq!(values, before);
+
+        let redo = changed_entries(&after, &values);
+        apply_entries(&mut values, redo);
+        assert_eq!(values, after);
+    }
+
+    #[test]
+    fn undo_and_redo_are_last_in_first_out() {
+        let mut history = History::new();
+        history.record(1);
+        history.record(2);
+        assert_eq!(history.pop_undo(), Some(2));
+        history.push_redo(3);
+        assert_eq!(history.pop_undo(), Some(1));
+        history.push_redo(4);
+        assert_eq!(history.pop_redo(), Some(4));
+        assert_eq!(history.pop_redo(), Some(3));
+        assert_eq!(history.pop_redo(), None);
+    }
+
+    #[test]
+    fn record_clears_redo() {
+        let mut history = History::new();
+        history.record(1);
+        history.pop_undo();
+        history.push_redo(2);
+        history.record(3);
+        assert_eq!(history.pop_redo(), None);
+    }
+
+    #[test]
+    fn oldest_change_is_dropped_when_full() {
+        let mut history = History::new();
+        for change in 0..HISTORY_SIZE + 1 {
+            history.record(change);
+        }
+        let mut undone = Vec::new();
+        while let Some(change) = history.pop_undo() {
+            undone.push(change);
+        }
+        assert_eq!(undone.len(), HISTORY_SIZE);
+        assert_eq!(undone.last(), Some(&1));
+    }
+}
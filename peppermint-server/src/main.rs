@@ -1,9 +1,19 @@
 use log::{info, warn};
 use structopt::StructOpt;
 
+pub mod atom_forwarder;
 pub mod backends;
+pub mod command_queue;
 pub mod grpc_service;
+pub mod history;
 pub mod manager;
+pub mod meters;
+pub mod plugin_state;
+pub mod reaper;
+pub mod recorder;
+pub mod render;
+pub mod session_events;
+pub mod streamer;
 
 #[derive(Debug, StructOpt)]
 struct Options {
@@ -15,6 +25,21 @@ struct Options {
 
     #[structopt(long, default_value = "4096")]
     command_queue_size: usize,
+
+    #[structopt(long, default_value = "4096")]
+    garbage_queue_size: usize,
+
+    #[structopt(long, default_value = "4096")]
+    atom_output_queue_size: usize,
+
+    #[structopt(long, default_value = "1")]
+    midi_inputs: usize,
+
+    #[structopt(long, default_value = "2")]
+    audio_inputs: usize,
+
+    #[structopt(long, default_value = "20")]
+    smoothing_ms: f64,
 }
 
 #[tokio::main]
@@ -26,31 +51,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .init();
 
     let (command_tx, command_rx) =
-        ringbuf::RingBuffer::<peppermint_core::command::Command>::new(options.command_queue_size)
+        ringbuf::RingBuffer::<peppermint_core::command::SequencedCommand>::new(
+            options.command_queue_size,
+        )
+        .split();
+    let (ack_tx, ack_rx) = ringbuf::RingBuffer::<u64>::new(command_queue::ACK_QUEUE_SIZE).split();
+    let (applied_commands_tx, applied_commands_rx) = tokio::sync::watch::channel(0);
+    let (garbage_tx, garbage_rx) =
+        ringbuf::RingBuffer::<peppermint_core::command::Garbage>::new(options.garbage_queue_size)
             .split();
+    let (atom_output_tx, atom_output_rx) = ringbuf::RingBuffer::<
+        peppermint_core::atom_output::AtomOutputEvent,
+    >::new(options.atom_output_queue_size)
+    .split();
 
     let addr = format!("127.0.0.1:{}", options.port).parse()?;
     let (sample_rate, buffer_size) = match options.backend {
         Backend::Dummy => backends::dummy::sample_rate_and_buffer_size(),
         Backend::Jack => backends::jack::sample_rate_and_buffer_size().unwrap(),
     };
+    let manager =
+        manager::PeppermintManager::new(sample_rate, buffer_size, command_tx, applied_commands_rx);
+    let master_track = manager.new_master_track();
+    let audio_streams = manager.audio_streams();
+    let lv2_features = manager.lv2_features();
+    let atom_output = manager.atom_output();
     let peppermint_service =
-        grpc_service::PeppermintServiceImpl::new(sample_rate, buffer_size, command_tx);
+        grpc_service::PeppermintServiceImpl::new(manager, std::time::Duration::from_millis(10));
     let server = tonic::transport::Server::builder()
         .add_service(peppermint_proto::peppermint_server::PeppermintServer::new(
             peppermint_service,
         ))
         .serve(addr);
 
+    let overflows = std::sync::Arc::new(peppermint_core::overflow::OverflowCounter::default());
+    let core_overflows = overflows.clone();
     info!("Running audio loop for backend {:?}.", options.backend);
     let _audio_thread = std::thread::spawn(move || {
-        let core = peppermint_core::PeppermintCore::new(command_rx);
+        let mut core = peppermint_core::PeppermintCore::new(command_rx, garbage_tx, master_track);
+        core.set_sample_rate(sample_rate);
+        core.set_smoothing_samples((options.smoothing_ms * sample_rate / 1000.0) as usize);
+        core.set_atom_output_queue(atom_output_tx);
+        core.set_ack_queue(ack_tx);
+        core.set_overflow_counter(core_overflows);
         match options.backend {
             Backend::Dummy => backends::dummy::run(core, buffer_size),
-            Backend::Jack => backends::jack::run(core).unwrap(),
+            Backend::Jack => {
+                backends::jack::run(core, options.midi_inputs, options.audio_inputs).unwrap()
+            }
         }
     });
 
+    let _command_ack_thread = std::thread::spawn(move || {
+        command_queue::run(
+            ack_rx,
+            applied_commands_tx,
+            std::time::Duration::from_millis(1),
+        );
+    });
+
+    let _reaper_thread = std::thread::spawn(move || {
+        reaper::run(garbage_rx, overflows, std::time::Duration::from_millis(100));
+    });
+
+    let _streamer_thread = std::thread::spawn(move || {
+        streamer::run(audio_streams, std::time::Duration::from_millis(10));
+    });
+
+    let _atom_forwarder_thread = std::thread::spawn(move || {
+        atom_forwarder::run(
+            atom_output_rx,
+            lv2_features,
+            atom_output,
+            std::time::Duration::from_millis(10),
+        );
+    });
+
     info!("peppermint is ready at {}.", addr);
     server.await?;
     warn!("Terminating peppermint.");
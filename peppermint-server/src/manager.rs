@@ -1,40 +1,246 @@
-use peppermint_core::command::Command;
+use crate::{
+    command_queue, history, meters, plugin_state, recorder, render, session_events, streamer,
+};
+use log::warn;
+use peppermint_core::command::{Command, Garbage, SequencedCommand};
 use ringbuf::Producer;
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
+/// The number of atom output events that are buffered for each client.
+const ATOM_OUTPUT_CAPACITY: usize = 1024;
+
+/// The number of session events that are buffered for each client.
+const SESSION_EVENTS_CAPACITY: usize = 1024;
+
 pub struct PeppermintManager {
     lv2_world: livi::World,
     lv2_features: Lv2Features,
-    commands: Producer<Command>,
+    commands: command_queue::CommandQueue,
+    applied_commands: tokio::sync::watch::Receiver<u64>,
     ids: IdManager,
     tracks: HashMap<peppermint_core::Id, peppermint_proto::Track>,
     plugin_instance_to_track: HashMap<peppermint_core::Id, peppermint_core::Id>,
+    send_to_track: HashMap<peppermint_core::Id, peppermint_core::Id>,
+    midi_clip_to_track: HashMap<peppermint_core::Id, peppermint_core::Id>,
+    audio_clip_to_track: HashMap<peppermint_core::Id, peppermint_core::Id>,
+    audio_streams: streamer::Streams,
+    recorders: HashMap<peppermint_core::Id, recorder::Recorder>,
+    meters: meters::Meters,
+    midi_bindings: HashMap<peppermint_core::Id, MidiBindingEntry>,
+    /// The state handles of the plugin instances that support the LV2 State
+    /// extension. Handles are removed as soon as their instance is deleted.
+    plugin_states: HashMap<peppermint_core::Id, plugin_state::StateHandle>,
+    /// The LV2 state of the plugin instances that were deleted by the change
+    /// that is being recorded in the history.
+    deleted_plugin_states: HashMap<peppermint_core::Id, Vec<u8>>,
+    /// The learned midi bindings that were removed by the change that is
+    /// being recorded in the history.
+    removed_midi_bindings: Vec<peppermint_proto::MidiBinding>,
+    history: history::History<Change>,
+    atom_output: tokio::sync::broadcast::Sender<peppermint_proto::AtomOutputEvent>,
+    session_events: tokio::sync::broadcast::Sender<peppermint_proto::SessionEvent>,
+    revision: u64,
+    tempo: peppermint_core::transport::Tempo,
+    pan_law: peppermint_core::pan::PanLaw,
     sample_rate: f64,
     buffer_size: usize,
 }
 
-struct Lv2Features(Arc<livi::Features>);
+/// The parts of the session that `session_diff` compares.
+#[derive(Clone, Default)]
+struct Snapshot {
+    tracks: HashMap<peppermint_core::Id, peppermint_proto::Track>,
+    /// The midi bindings that have learned a controller.
+    midi_bindings: Vec<peppermint_proto::MidiBinding>,
+    pan_law: peppermint_core::pan::PanLaw,
+    tempo: peppermint_core::transport::Tempo,
+    /// The directory that relative paths in the LV2 state of plugin instances
+    /// are resolved against.
+    plugin_state_dir: Option<std::path::PathBuf>,
+}
+
+/// The inverse of a change to the session. Only the tracks that the change
+/// touched are kept.
+struct Change {
+    /// The tracks as they were before the change, or `None` for tracks that
+    /// the change created. Plugin instances that the change deleted include
+    /// their LV2 state.
+    tracks: Vec<(peppermint_core::Id, Option<peppermint_proto::Track>)>,
+    /// The learned midi bindings that the change removed.
+    midi_bindings: Vec<peppermint_proto::MidiBinding>,
+    pan_law: peppermint_core::pan::PanLaw,
+    tempo: peppermint_core::transport::Tempo,
+}
+
+/// The commands that change the session on a core along with the state that
+/// they share with the manager.
+#[derive(Default)]
+struct Session {
+    commands: Vec<Command>,
+    /// The streams for new audio clips keyed by clip id.
+    streams: Vec<(peppermint_core::Id, streamer::Stream)>,
+    /// The new regions of existing audio clips.
+    audio_clip_regions: Vec<(
+        peppermint_core::Id,
+        peppermint_core::audio_clip::AudioClipRegion,
+    )>,
+    /// The meters of the tracks that are created by `commands`.
+    meters: Vec<(peppermint_core::Id, Arc<peppermint_core::meter::Meter>)>,
+    /// The state handles of the plugin instances that are created by
+    /// `commands`.
+    plugin_states: Vec<(peppermint_core::Id, plugin_state::StateHandle)>,
+}
+
+/// A midi binding and the state that it shares with the audio thread.
+struct MidiBindingEntry {
+    binding: peppermint_proto::MidiBinding,
+    state: Arc<peppermint_core::midi_learn::MidiBindingState>,
+    /// The last value from `state` that was copied into the session.
+    applied_value: Option<f32>,
+}
+
+/// The LV2 features that are shared by all plugin instances.
+#[derive(Clone)]
+pub struct Lv2Features(pub Arc<livi::Features>);
 unsafe impl Send for Lv2Features {}
 
 impl PeppermintManager {
-    pub fn new(sample_rate: f64, buffer_size: usize, commands: Producer<Command>) -> Self {
+    /// Create a manager that sends commands to `commands`. `applied_commands`
+    /// should hold the sequence number of the last command that the core
+    /// applied.
+    pub fn new(
+        sample_rate: f64,
+        buffer_size: usize,
+        commands: Producer<SequencedCommand>,
+        applied_commands: tokio::sync::watch::Receiver<u64>,
+    ) -> Self {
         let lv2_world = livi::World::new();
         let lv2_features = Lv2Features(lv2_world.build_features(livi::FeaturesBuilder::default()));
+        let mut ids = IdManager::new();
+        ids.register_id(peppermint_core::MASTER_TRACK_ID);
+        let master_track = peppermint_proto::Track {
+            id: peppermint_core::MASTER_TRACK_ID,
+            name: "Master".to_string(),
+            gain: 1.0,
+            plugin_instances: Vec::new(),
+            midi_input: Some(midi_input_to_proto(
+                &peppermint_core::midi::MidiInput::default(),
+            )),
+            pan: 0.0,
+            mute: false,
+            solo: false,
+            sends: Vec::new(),
+            audio_input: Some(audio_input_to_proto(
+                &peppermint_core::track::AudioInput::default(),
+            )),
+            midi_clips: Vec::new(),
+            audio_clips: Vec::new(),
+            midi_output: false,
+        };
         PeppermintManager {
             lv2_world,
             lv2_features,
-            commands,
-            ids: IdManager::new(),
-            tracks: HashMap::new(),
+            commands: command_queue::CommandQueue::new(commands),
+            applied_commands,
+            ids,
+            tracks: std::iter::once((master_track.id, master_track)).collect(),
             plugin_instance_to_track: HashMap::new(),
+            send_to_track: HashMap::new(),
+            midi_clip_to_track: HashMap::new(),
+            audio_clip_to_track: HashMap::new(),
+            audio_streams: Arc::new(Mutex::new(HashMap::new())),
+            recorders: HashMap::new(),
+            meters: Arc::new(Mutex::new(HashMap::new())),
+            midi_bindings: HashMap::new(),
+            plugin_states: HashMap::new(),
+            deleted_plugin_states: HashMap::new(),
+            removed_midi_bindings: Vec::new(),
+            history: history::History::new(),
+            atom_output: tokio::sync::broadcast::channel(ATOM_OUTPUT_CAPACITY).0,
+            session_events: tokio::sync::broadcast::channel(SESSION_EVENTS_CAPACITY).0,
+            revision: 0,
+            tempo: peppermint_core::transport::Tempo::default(),
+            pan_law: peppermint_core::pan::PanLaw::default(),
             sample_rate,
             buffer_size,
         }
     }
 
+    /// Create the master track for `PeppermintCore`.
+    pub fn new_master_track(&self) -> peppermint_core::track::Track {
+        let track = peppermint_core::track::Track::new(
+            peppermint_core::MASTER_TRACK_ID,
+            self.buffer_size,
+            &self.lv2_features.0,
+        );
+        if let Ok(mut meters) = self.meters.lock() {
+            meters.insert(track.id(), track.meter());
+        }
+        track
+    }
+
+    /// The meters of all tracks, including the master track.
+    pub fn meters(&self) -> meters::Meters {
+        self.meters.clone()
+    }
+
+    /// The LV2 features that are used by all plugin instances.
+    pub fn lv2_features(&self) -> Lv2Features {
+        self.lv2_features.clone()
+    }
+
+    /// The sender for atom output events. Events should be sent with
+    /// `atom_forwarder::run`.
+    pub fn atom_output(&self) -> tokio::sync::broadcast::Sender<peppermint_proto::AtomOutputEvent> {
+        self.atom_output.clone()
+    }
+
+    /// Subscribe to the atom output events of all plugin instances.
+    pub fn subscribe_atom_output(
+        &self,
+    ) -> tokio::sync::broadcast::Receiver<peppermint_proto::AtomOutputEvent> {
+        self.atom_output.subscribe()
+    }
+
+    /// Returns a future that completes once the audio thread has applied every
+    /// command that has been sent so far or fails after `timeout`.
+    pub fn wait_for_commands(
+        &self,
+        timeout: std::time::Duration,
+    ) -> impl std::future::Future<Output = Result<(), tonic::Status>> {
+        command_queue::wait(
+            self.applied_commands.clone(),
+            self.commands.last_sequence(),
+            timeout,
+        )
+    }
+
+    /// Subscribe to changes to tracks and plugin instances.
+    pub fn subscribe_session(
+        &self,
+    ) -> tokio::sync::broadcast::Receiver<peppermint_proto::SessionEvent> {
+        self.session_events.subscribe()
+    }
+
+    /// Send the events for the changes from `before` to the current tracks.
+    fn publish_changes(&mut self, before: &HashMap<peppermint_core::Id, peppermint_proto::Track>) {
+        for mut event in session_events::diff(before, &self.tracks) {
+            self.revision += 1;
+            event.revision = self.revision;
+            // Sending only fails if there are no subscribers.
+            self.session_events.send(event).ok();
+        }
+    }
+
+    /// The streams that feed the audio clips of all tracks. They should be
+    /// filled with `streamer::run`.
+    pub fn audio_streams(&self) -> streamer::Streams {
+        self.audio_streams.clone()
+    }
+
     fn plugin_by_id(&self, id: &str) -> Option<livi::Plugin> {
         self.lv2_world
             .iter_plugins()
@@ -74,6 +280,8 @@ impl PeppermintManager {
         tracks.sort_by_key(|t| t.id);
         Ok(tonic::Response::new(peppermint_proto::GetTracksResponse {
             tracks,
+            master_track_id: peppermint_core::MASTER_TRACK_ID,
+            revision: self.revision,
         }))
     }
 
@@ -81,6 +289,11 @@ impl PeppermintManager {
         &mut self,
         req: tonic::Request<peppermint_proto::CreateTrackRequest>,
     ) -> Result<tonic::Response<peppermint_proto::CreateTrackResponse>, tonic::Status> {
+        // `tracks` includes the master track which does not count toward the
+        // limit.
+        check_capacity(self.tracks.len(), peppermint_core::MAX_TRACKS, || {
+            "the number of tracks".to_string()
+        })?;
         let track_id = match req.get_ref().track_id {
             0 => self.ids.next_id(),
             id => self.ids.register_id(id).ok_or_else(|| {
@@ -99,11 +312,25 @@ impl PeppermintManager {
             name: track_name,
             gain: core_track.property(peppermint_core::track::TrackProperty::Gain),
             plugin_instances: Vec::new(),
+            midi_input: Some(midi_input_to_proto(&core_track.midi_input())),
+            pan: core_track.property(peppermint_core::track::TrackProperty::Pan),
+            mute: core_track.is_muted(),
+            solo: core_track.is_soloed(),
+            sends: Vec::new(),
+            audio_input: Some(audio_input_to_proto(&core_track.audio_input())),
+            midi_clips: Vec::new(),
+            audio_clips: Vec::new(),
+            midi_output: core_track.property(peppermint_core::track::TrackProperty::MidiOutput)
+                > 0.5,
         };
+        let meter = core_track.meter();
         self.commands
             .push(Command::CreateTrack(core_track))
             .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
         self.tracks.insert(track_id, proto_track.clone());
+        if let Ok(mut meters) = self.meters.lock() {
+            meters.insert(track_id, meter);
+        }
         Ok(tonic::Response::new(
             peppermint_proto::CreateTrackResponse {
                 track: Some(proto_track),
@@ -116,20 +343,60 @@ impl PeppermintManager {
         req: tonic::Request<peppermint_proto::DeleteTrackRequest>,
     ) -> Result<tonic::Response<peppermint_proto::DeleteTrackResponse>, tonic::Status> {
         let track_id = req.get_ref().track_id;
+        if track_id == peppermint_core::MASTER_TRACK_ID {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "the master track can not be deleted",
+            ));
+        }
         let track = self.tracks.remove(&track_id).ok_or_else(|| {
             tonic::Status::new(
                 tonic::Code::NotFound,
                 format!("track {} not found", track_id),
             )
         })?;
+        // The instances may be deallocated as soon as the command is sent.
+        for plugin_instance in track.plugin_instances.iter() {
+            self.save_deleted_plugin_state(plugin_instance.id);
+        }
         self.ids.release_id(track_id);
+        if let Ok(mut meters) = self.meters.lock() {
+            meters.remove(&track_id);
+        }
         for plugin_instance in track.plugin_instances.iter() {
             self.ids.release_id(plugin_instance.id);
             self.plugin_instance_to_track.remove(&plugin_instance.id);
+            self.plugin_states.remove(&plugin_instance.id);
+        }
+        for send in track.sends.iter() {
+            self.ids.release_id(send.id);
+            self.send_to_track.remove(&send.id);
+        }
+        for clip in track.midi_clips.iter() {
+            self.ids.release_id(clip.id);
+            self.midi_clip_to_track.remove(&clip.id);
+        }
+        for clip in track.audio_clips.iter() {
+            self.ids.release_id(clip.id);
+            self.audio_clip_to_track.remove(&clip.id);
+            self.remove_audio_stream(clip.id);
+        }
+        for other_track in self.tracks.values_mut() {
+            for send in other_track.sends.iter() {
+                if send.destination_track_id == track_id {
+                    self.ids.release_id(send.id);
+                    self.send_to_track.remove(&send.id);
+                }
+            }
+            other_track
+                .sends
+                .retain(|send| send.destination_track_id != track_id);
         }
         self.commands
             .push(Command::DeleteTrack(track_id))
             .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        let plugin_instance_ids: Vec<_> = track.plugin_instances.iter().map(|p| p.id).collect();
+        self.remove_midi_bindings_for(Some(track_id), &plugin_instance_ids)?;
         Ok(tonic::Response::new(
             peppermint_proto::DeleteTrackResponse {},
         ))
@@ -146,28 +413,74 @@ impl PeppermintManager {
                 format!("track {} not found", track_id),
             )
         })?;
+        let midi_input = req
+            .get_ref()
+            .midi_input
+            .as_ref()
+            .map(midi_input_from_proto)
+            .transpose()?;
+        let audio_input = req
+            .get_ref()
+            .audio_input
+            .as_ref()
+            .map(audio_input_from_proto)
+            .transpose()?;
         if !req.get_ref().name.is_empty() {
             track.name = req.get_ref().name.clone();
         }
+        if let Some(midi_input) = midi_input {
+            self.commands
+                .push(Command::UpdateTrackMidiInput(track_id, midi_input))
+                .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+            track.midi_input = Some(midi_input_to_proto(&midi_input));
+        }
+        if let Some(audio_input) = audio_input {
+            self.commands
+                .push(Command::UpdateTrackAudioInput(track_id, audio_input))
+                .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+            track.audio_input = Some(audio_input_to_proto(&audio_input));
+        }
         for update in req.get_ref().updates.iter() {
             let value = update.value;
             let property =
                 peppermint_proto::track_property_update::TrackProperty::from_i32(update.property)
                     .unwrap_or(peppermint_proto::track_property_update::TrackProperty::Undefined);
-            match property {
-                peppermint_proto::track_property_update::TrackProperty::Undefined => (),
+            let (core_property, value) = match property {
+                peppermint_proto::track_property_update::TrackProperty::Undefined => continue,
                 peppermint_proto::track_property_update::TrackProperty::Gain => {
                     track.gain = value;
-                    let command = Command::UpdateTrack(
-                        track_id,
-                        peppermint_core::track::TrackProperty::Gain,
-                        value,
-                    );
-                    self.commands.push(command).map_err(|_| {
-                        tonic::Status::new(tonic::Code::Internal, "failed to send command")
-                    })?;
+                    (peppermint_core::track::TrackProperty::Gain, value)
                 }
-            }
+                peppermint_proto::track_property_update::TrackProperty::Pan => {
+                    let value = value.clamp(-1.0, 1.0);
+                    track.pan = value;
+                    (peppermint_core::track::TrackProperty::Pan, value)
+                }
+                peppermint_proto::track_property_update::TrackProperty::Mute => {
+                    track.mute = value > 0.5;
+                    (
+                        peppermint_core::track::TrackProperty::Mute,
+                        bool_to_f32(track.mute),
+                    )
+                }
+                peppermint_proto::track_property_update::TrackProperty::Solo => {
+                    track.solo = value > 0.5;
+                    (
+                        peppermint_core::track::TrackProperty::Solo,
+                        bool_to_f32(track.solo),
+                    )
+                }
+                peppermint_proto::track_property_update::TrackProperty::MidiOutput => {
+                    track.midi_output = value > 0.5;
+                    (
+                        peppermint_core::track::TrackProperty::MidiOutput,
+                        bool_to_f32(track.midi_output),
+                    )
+                }
+            };
+            self.commands
+                .push(Command::UpdateTrack(track_id, core_property, value))
+                .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
         }
         Ok(tonic::Response::new(
             peppermint_proto::UpdateTrackResponse {},
@@ -203,21 +516,35 @@ impl PeppermintManager {
                     format!("track {} not found", req.get_ref().track_id),
                 )
             })?;
+        check_capacity(
+            track.plugin_instances.len() + 1,
+            peppermint_core::track::MAX_PLUGIN_INSTANCES,
+            || format!("the number of plugin instances on track {}", track.id),
+        )?;
         let track_core_id = track.id;
         let params: Vec<f32> = plugin
             .ports_with_type(livi::PortType::ControlInput)
             .map(|port| port.default_value)
             .collect();
-        let plugin_instance_id = self.ids.next_id();
+        let plugin_instance_id = match req.get_ref().id {
+            0 => self.ids.next_id(),
+            id => self.ids.register_id(id).ok_or_else(|| {
+                tonic::Status::already_exists(format!("id {} already exists", id))
+            })?,
+        };
         track
             .plugin_instances
             .push(peppermint_proto::PluginInstance {
                 id: plugin_instance_id,
                 plugin_id: req.get_ref().plugin_id.clone(),
                 params,
+                bypassed: false,
+                mix: 1.0,
+                state: Vec::new(),
             });
         self.plugin_instance_to_track
             .insert(plugin_instance_id, track_core_id);
+        let state = plugin_state::StateHandle::new(&instance);
         let command = Command::PushPluginInstance {
             id: plugin_instance_id,
             track: track_core_id,
@@ -226,6 +553,9 @@ impl PeppermintManager {
         self.commands
             .push(command)
             .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        if let Some(state) = state {
+            self.plugin_states.insert(plugin_instance_id, state);
+        }
         Ok(tonic::Response::new(
             peppermint_proto::InstantiatePluginResponse {
                 id: plugin_instance_id,
@@ -247,7 +577,10 @@ impl PeppermintManager {
                     format!("plugin instance {} not found", req.get_ref().id),
                 )
             })?;
-        let track = self.tracks.get_mut(track_id).ok_or_else(|| {
+        let track_id = *track_id;
+        // The instance may be deallocated as soon as the command is sent.
+        self.save_deleted_plugin_state(req.get_ref().id);
+        let track = self.tracks.get_mut(&track_id).ok_or_else(|| {
             tonic::Status::new(
                 tonic::Code::Internal,
                 format!("associated track {} not found", track_id),
@@ -274,56 +607,2757 @@ impl PeppermintManager {
             .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
         track.plugin_instances.remove(plugin_instance_index);
         self.plugin_instance_to_track.remove(&req.get_ref().id);
+        self.plugin_states.remove(&req.get_ref().id);
         self.ids.release_id(req.get_ref().id);
+        self.remove_midi_bindings_for(None, &[req.get_ref().id])?;
 
         Ok(tonic::Response::new(
             peppermint_proto::DeletePluginInstanceResponse {},
         ))
     }
-}
 
-pub struct IdManager {
-    next_id: peppermint_core::Id,
-    all_ids: HashSet<peppermint_core::Id>,
-}
+    pub fn move_plugin_instance(
+        &mut self,
+        req: tonic::Request<peppermint_proto::MovePluginInstanceRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::MovePluginInstanceResponse>, tonic::Status> {
+        let plugin_instance_id = req.get_ref().id;
+        let target_track_id = req.get_ref().track_id;
+        let source_track_id = *self
+            .plugin_instance_to_track
+            .get(&plugin_instance_id)
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("plugin instance {} not found", plugin_instance_id),
+                )
+            })?;
+        let target_len = self
+            .tracks
+            .get(&target_track_id)
+            .map(|t| t.plugin_instances.len())
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("track {} not found", target_track_id),
+                )
+            })?;
+        let max_index = if source_track_id == target_track_id {
+            target_len - 1
+        } else {
+            check_capacity(
+                target_len + 1,
+                peppermint_core::track::MAX_PLUGIN_INSTANCES,
+                || {
+                    format!(
+                        "the number of plugin instances on track {}",
+                        target_track_id
+                    )
+                },
+            )?;
+            target_len
+        };
+        let index = req.get_ref().index as usize;
+        if index > max_index {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "index {} is out of range for track {}",
+                    index, target_track_id
+                ),
+            ));
+        }
+        let source_track = self.tracks.get_mut(&source_track_id).ok_or_else(|| {
+            tonic::Status::new(
+                tonic::Code::Internal,
+                format!("associated track {} not found", source_track_id),
+            )
+        })?;
+        let plugin_instance_index = source_track
+            .plugin_instances
+            .iter()
+            .position(|p| p.id == plugin_instance_id)
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::Internal,
+                    format!(
+                        "plugin instance {} not found within tracks",
+                        plugin_instance_id
+                    ),
+                )
+            })?;
 
-impl IdManager {
-    pub fn new() -> IdManager {
-        IdManager {
-            next_id: 1,
-            all_ids: HashSet::new(),
+        self.commands
+            .push(Command::MovePluginInstance {
+                id: plugin_instance_id,
+                track: target_track_id,
+                index,
+            })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        let plugin_instance = source_track.plugin_instances.remove(plugin_instance_index);
+        if let Some(target_track) = self.tracks.get_mut(&target_track_id) {
+            target_track.plugin_instances.insert(index, plugin_instance);
         }
+        self.plugin_instance_to_track
+            .insert(plugin_instance_id, target_track_id);
+        Ok(tonic::Response::new(
+            peppermint_proto::MovePluginInstanceResponse {},
+        ))
     }
 
-    pub fn next_id(&mut self) -> peppermint_core::Id {
-        while self.all_ids.contains(&self.next_id) {
-            self.next_id += 1;
+    pub fn update_plugin_instance(
+        &mut self,
+        req: tonic::Request<peppermint_proto::UpdatePluginInstanceRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::UpdatePluginInstanceResponse>, tonic::Status>
+    {
+        let plugin_instance_id = req.get_ref().id;
+        let track_id = self
+            .plugin_instance_to_track
+            .get(&plugin_instance_id)
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("plugin instance {} not found", plugin_instance_id),
+                )
+            })?;
+        let plugin_id = self
+            .tracks
+            .get(track_id)
+            .and_then(|t| {
+                t.plugin_instances
+                    .iter()
+                    .find(|p| p.id == plugin_instance_id)
+            })
+            .map(|p| p.plugin_id.clone())
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::Internal,
+                    format!(
+                        "plugin instance {} not found within tracks",
+                        plugin_instance_id
+                    ),
+                )
+            })?;
+        let plugin = self.plugin_by_id(&plugin_id).ok_or_else(|| {
+            tonic::Status::new(
+                tonic::Code::Internal,
+                format!("plugin {} not found", plugin_id),
+            )
+        })?;
+        let ports: Vec<livi::Port> = plugin
+            .ports_with_type(livi::PortType::ControlInput)
+            .collect();
+        for update in req.get_ref().updates.iter() {
+            if ports.get(update.index as usize).is_none() {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    format!(
+                        "param {} out of range for plugin {}",
+                        update.index, plugin_id
+                    ),
+                ));
+            }
         }
-        let id = self.next_id;
-        self.next_id += 1;
-        id
+
+        let plugin_instance = self
+            .tracks
+            .get_mut(track_id)
+            .and_then(|t| {
+                t.plugin_instances
+                    .iter_mut()
+                    .find(|p| p.id == plugin_instance_id)
+            })
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::Internal,
+                    format!(
+                        "plugin instance {} not found within tracks",
+                        plugin_instance_id
+                    ),
+                )
+            })?;
+        // Either all updates are sent or none are.
+        let property_update_count = req
+            .get_ref()
+            .property_updates
+            .iter()
+            .filter(|update| {
+                !matches!(
+                    peppermint_proto::plugin_instance_property_update::PluginInstanceProperty::from_i32(update.property),
+                    None | Some(peppermint_proto::plugin_instance_property_update::PluginInstanceProperty::Undefined)
+                )
+            })
+            .count();
+        if self.commands.remaining() < req.get_ref().updates.len() + property_update_count {
+            return Err(tonic::Status::new(
+                tonic::Code::ResourceExhausted,
+                "too many updates for the command queue",
+            ));
+        }
+        for update in req.get_ref().updates.iter() {
+            let port = &ports[update.index as usize];
+            let value = clamp_to_port(port, update.value);
+            let command = Command::UpdatePluginInstance {
+                id: plugin_instance_id,
+                port: port.index,
+                value,
+            };
+            self.commands
+                .push(command)
+                .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+            plugin_instance.params[update.index as usize] = value;
+        }
+        for update in req.get_ref().property_updates.iter() {
+            let property = peppermint_proto::plugin_instance_property_update::PluginInstanceProperty::from_i32(update.property)
+                .unwrap_or(peppermint_proto::plugin_instance_property_update::PluginInstanceProperty::Undefined);
+            let (core_property, value) = match property {
+                peppermint_proto::plugin_instance_property_update::PluginInstanceProperty::Undefined => continue,
+                peppermint_proto::plugin_instance_property_update::PluginInstanceProperty::Bypass => (
+                    peppermint_core::track::InstanceProperty::Bypass,
+                    bool_to_f32(update.value > 0.5),
+                ),
+                peppermint_proto::plugin_instance_property_update::PluginInstanceProperty::Mix => (
+                    peppermint_core::track::InstanceProperty::Mix,
+                    update.value.clamp(0.0, 1.0),
+                ),
+            };
+            let command = Command::UpdatePluginInstanceProperty {
+                id: plugin_instance_id,
+                property: core_property,
+                value,
+            };
+            self.commands
+                .push(command)
+                .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+            match core_property {
+                peppermint_core::track::InstanceProperty::Bypass => {
+                    plugin_instance.bypassed = value > 0.5
+                }
+                peppermint_core::track::InstanceProperty::Mix => plugin_instance.mix = value,
+            }
+        }
+        Ok(tonic::Response::new(
+            peppermint_proto::UpdatePluginInstanceResponse {},
+        ))
     }
 
-    pub fn register_id(&mut self, id: peppermint_core::Id) -> Option<peppermint_core::Id> {
-        if self.all_ids.contains(&id) {
-            None
-        } else {
-            self.all_ids.insert(id);
-            Some(id)
+    pub fn set_pan_law(
+        &mut self,
+        req: tonic::Request<peppermint_proto::SetPanLawRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::SetPanLawResponse>, tonic::Status> {
+        let pan_law = pan_law_from_proto(req.get_ref().pan_law).ok_or_else(|| {
+            tonic::Status::new(tonic::Code::InvalidArgument, "pan law must be specified")
+        })?;
+        self.commands
+            .push(Command::SetPanLaw(pan_law))
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        self.pan_law = pan_law;
+        Ok(tonic::Response::new(peppermint_proto::SetPanLawResponse {}))
+    }
+
+    pub fn create_send(
+        &mut self,
+        req: tonic::Request<peppermint_proto::CreateSendRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::CreateSendResponse>, tonic::Status> {
+        let source_track_id = req.get_ref().source_track_id;
+        let destination_track_id = req.get_ref().destination_track_id;
+        validate_send_level(req.get_ref().level)?;
+        for track_id in [source_track_id, destination_track_id] {
+            if track_id == peppermint_core::MASTER_TRACK_ID {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    "the master track can not be used in a send",
+                ));
+            }
+            if !self.tracks.contains_key(&track_id) {
+                return Err(tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("track {} not found", track_id),
+                ));
+            }
+        }
+        if self.track_feeds_into(destination_track_id, source_track_id) {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "send from track {} to track {} would create a cycle",
+                    source_track_id, destination_track_id
+                ),
+            ));
+        }
+        check_capacity(
+            self.tracks[&source_track_id].sends.len() + 1,
+            peppermint_core::track::MAX_SENDS,
+            || format!("the number of sends on track {}", source_track_id),
+        )?;
+        let send_id = self.ids.next_id();
+        let send = peppermint_core::track::AuxSend {
+            id: send_id,
+            destination: destination_track_id,
+            level: req.get_ref().level,
+            pre_fader: req.get_ref().pre_fader,
+        };
+        self.commands
+            .push(Command::CreateSend {
+                track: source_track_id,
+                send,
+            })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        let proto_send = peppermint_proto::Send {
+            id: send_id,
+            destination_track_id,
+            level: send.level,
+            pre_fader: send.pre_fader,
+        };
+        if let Some(track) = self.tracks.get_mut(&source_track_id) {
+            track.sends.push(proto_send.clone());
         }
+        self.send_to_track.insert(send_id, source_track_id);
+        Ok(tonic::Response::new(peppermint_proto::CreateSendResponse {
+            send: Some(proto_send),
+        }))
     }
 
-    pub fn release_id(&mut self, id: peppermint_core::Id) {
-        self.all_ids.remove(&id);
+    pub fn update_send(
+        &mut self,
+        req: tonic::Request<peppermint_proto::UpdateSendRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::UpdateSendResponse>, tonic::Status> {
+        let send_id = req.get_ref().id;
+        validate_send_level(req.get_ref().level)?;
+        let send = self
+            .send_to_track
+            .get(&send_id)
+            .and_then(|track_id| self.tracks.get_mut(track_id))
+            .and_then(|track| track.sends.iter_mut().find(|s| s.id == send_id))
+            .ok_or_else(|| {
+                tonic::Status::new(tonic::Code::NotFound, format!("send {} not found", send_id))
+            })?;
+        self.commands
+            .push(Command::UpdateSend {
+                id: send_id,
+                level: req.get_ref().level,
+                pre_fader: req.get_ref().pre_fader,
+            })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        send.level = req.get_ref().level;
+        send.pre_fader = req.get_ref().pre_fader;
+        Ok(tonic::Response::new(
+            peppermint_proto::UpdateSendResponse {},
+        ))
     }
-}
 
-impl Default for IdManager {
-    fn default() -> Self {
-        Self::new()
+    pub fn delete_send(
+        &mut self,
+        req: tonic::Request<peppermint_proto::DeleteSendRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::DeleteSendResponse>, tonic::Status> {
+        let send_id = req.get_ref().id;
+        let track = self
+            .send_to_track
+            .get(&send_id)
+            .and_then(|track_id| self.tracks.get_mut(track_id))
+            .ok_or_else(|| {
+                tonic::Status::new(tonic::Code::NotFound, format!("send {} not found", send_id))
+            })?;
+        self.commands
+            .push(Command::DeleteSend { id: send_id })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        track.sends.retain(|s| s.id != send_id);
+        self.send_to_track.remove(&send_id);
+        self.ids.release_id(send_id);
+        Ok(tonic::Response::new(
+            peppermint_proto::DeleteSendResponse {},
+        ))
     }
-}
 
-fn lv2_plugin_id(p: &livi::Plugin) -> String {
-    format!("lv2{}", p.uri())
+    pub fn play(
+        &mut self,
+    ) -> Result<tonic::Response<peppermint_proto::PlayResponse>, tonic::Status> {
+        self.commands
+            .push(Command::Play)
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        Ok(tonic::Response::new(peppermint_proto::PlayResponse {}))
+    }
+
+    pub fn stop(
+        &mut self,
+    ) -> Result<tonic::Response<peppermint_proto::StopResponse>, tonic::Status> {
+        self.commands
+            .push(Command::Stop)
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        Ok(tonic::Response::new(peppermint_proto::StopResponse {}))
+    }
+
+    pub fn seek(
+        &mut self,
+        req: tonic::Request<peppermint_proto::SeekRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::SeekResponse>, tonic::Status> {
+        self.commands
+            .push(Command::Seek(req.get_ref().frame))
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        Ok(tonic::Response::new(peppermint_proto::SeekResponse {}))
+    }
+
+    pub fn set_tempo(
+        &mut self,
+        req: tonic::Request<peppermint_proto::SetTempoRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::SetTempoResponse>, tonic::Status> {
+        let mut tempo = self.tempo;
+        let bpm = req.get_ref().beats_per_minute;
+        if bpm.is_finite() && bpm > 0.0 {
+            tempo.beats_per_minute = bpm;
+        } else if bpm != 0.0 {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("{} is not a valid tempo", bpm),
+            ));
+        }
+        if req.get_ref().beats_per_bar != 0 {
+            tempo.beats_per_bar = req.get_ref().beats_per_bar;
+        }
+        if req.get_ref().beat_unit != 0 {
+            tempo.beat_unit = req.get_ref().beat_unit;
+        }
+        self.commands
+            .push(Command::SetTempo(tempo))
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        self.tempo = tempo;
+        Ok(tonic::Response::new(peppermint_proto::SetTempoResponse {}))
+    }
+
+    pub fn create_midi_clip(
+        &mut self,
+        req: tonic::Request<peppermint_proto::CreateMidiClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::CreateMidiClipResponse>, tonic::Status> {
+        let track_id = req.get_ref().track_id;
+        if !self.tracks.contains_key(&track_id) {
+            return Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("track {} not found", track_id),
+            ));
+        }
+        check_capacity(
+            self.tracks[&track_id].midi_clips.len() + 1,
+            peppermint_core::sequencer::MAX_MIDI_CLIPS,
+            || format!("the number of midi clips on track {}", track_id),
+        )?;
+        let mut proto_clip = req.get_ref().clip.clone().unwrap_or_default();
+        let mut clip = midi_clip_from_proto(&proto_clip)?;
+        let clip_id = self.ids.next_id();
+        clip.id = clip_id;
+        proto_clip.id = clip_id;
+        self.commands
+            .push(Command::SetMidiClip {
+                track: track_id,
+                clip,
+            })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        if let Some(track) = self.tracks.get_mut(&track_id) {
+            track.midi_clips.push(proto_clip.clone());
+        }
+        self.midi_clip_to_track.insert(clip_id, track_id);
+        Ok(tonic::Response::new(
+            peppermint_proto::CreateMidiClipResponse {
+                clip: Some(proto_clip),
+            },
+        ))
+    }
+
+    pub fn update_midi_clip(
+        &mut self,
+        req: tonic::Request<peppermint_proto::UpdateMidiClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::UpdateMidiClipResponse>, tonic::Status> {
+        let proto_clip = req.get_ref().clip.clone().unwrap_or_default();
+        let clip = midi_clip_from_proto(&proto_clip)?;
+        let track_id = *self.midi_clip_to_track.get(&proto_clip.id).ok_or_else(|| {
+            tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("midi clip {} not found", proto_clip.id),
+            )
+        })?;
+        self.commands
+            .push(Command::SetMidiClip {
+                track: track_id,
+                clip,
+            })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        if let Some(existing) = self
+            .tracks
+            .get_mut(&track_id)
+            .and_then(|t| t.midi_clips.iter_mut().find(|c| c.id == proto_clip.id))
+        {
+            *existing = proto_clip;
+        }
+        Ok(tonic::Response::new(
+            peppermint_proto::UpdateMidiClipResponse {},
+        ))
+    }
+
+    pub fn delete_midi_clip(
+        &mut self,
+        req: tonic::Request<peppermint_proto::DeleteMidiClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::DeleteMidiClipResponse>, tonic::Status> {
+        let clip_id = req.get_ref().id;
+        let track = self
+            .midi_clip_to_track
+            .get(&clip_id)
+            .and_then(|track_id| self.tracks.get_mut(track_id))
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("midi clip {} not found", clip_id),
+                )
+            })?;
+        self.commands
+            .push(Command::DeleteMidiClip { id: clip_id })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        track.midi_clips.retain(|c| c.id != clip_id);
+        self.midi_clip_to_track.remove(&clip_id);
+        self.ids.release_id(clip_id);
+        Ok(tonic::Response::new(
+            peppermint_proto::DeleteMidiClipResponse {},
+        ))
+    }
+
+    pub fn create_audio_clip(
+        &mut self,
+        req: tonic::Request<peppermint_proto::CreateAudioClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::CreateAudioClipResponse>, tonic::Status> {
+        let track_id = req.get_ref().track_id;
+        if !self.tracks.contains_key(&track_id) {
+            return Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("track {} not found", track_id),
+            ));
+        }
+        check_capacity(
+            self.tracks[&track_id].audio_clips.len() + 1,
+            peppermint_core::track::MAX_AUDIO_CLIPS,
+            || format!("the number of audio clips on track {}", track_id),
+        )?;
+        let path = &req.get_ref().path;
+        let decoder = streamer::Decoder::open(std::path::Path::new(path)).map_err(|e| {
+            tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("failed to open {}: {}", path, e),
+            )
+        })?;
+        if decoder.sample_rate() != self.sample_rate {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "{} has a sample rate of {} but {} is required",
+                    path,
+                    decoder.sample_rate(),
+                    self.sample_rate
+                ),
+            ));
+        }
+        let region = peppermint_core::audio_clip::AudioClipRegion {
+            start: req.get_ref().start,
+            offset: 0,
+            length: decoder.frames(),
+        };
+        let clip_id = self.ids.next_id();
+        let proto_clip = peppermint_proto::AudioClip {
+            id: clip_id,
+            path: path.clone(),
+            start: region.start,
+            offset: region.offset,
+            length: region.length,
+            file_length: decoder.frames(),
+        };
+        let (stream, clip) = streamer::Stream::new(clip_id, decoder, region);
+        self.commands
+            .push(Command::CreateAudioClip {
+                track: track_id,
+                clip,
+            })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        self.audio_streams
+            .lock()
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "audio streams are poisoned"))?
+            .insert(clip_id, stream);
+        if let Some(track) = self.tracks.get_mut(&track_id) {
+            track.audio_clips.push(proto_clip.clone());
+        }
+        self.audio_clip_to_track.insert(clip_id, track_id);
+        Ok(tonic::Response::new(
+            peppermint_proto::CreateAudioClipResponse {
+                clip: Some(proto_clip),
+            },
+        ))
+    }
+
+    pub fn update_audio_clip(
+        &mut self,
+        req: tonic::Request<peppermint_proto::UpdateAudioClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::UpdateAudioClipResponse>, tonic::Status> {
+        let clip_id = req.get_ref().id;
+        let proto_clip = self
+            .audio_clip_to_track
+            .get(&clip_id)
+            .and_then(|track_id| self.tracks.get_mut(track_id))
+            .and_then(|track| track.audio_clips.iter_mut().find(|c| c.id == clip_id))
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("audio clip {} not found", clip_id),
+                )
+            })?;
+        let region = peppermint_core::audio_clip::AudioClipRegion {
+            start: req.get_ref().start,
+            offset: req.get_ref().offset,
+            length: req.get_ref().length,
+        };
+        if region.offset.saturating_add(region.length) > proto_clip.file_length {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "offset {} and length {} exceed the file length of {}",
+                    region.offset, region.length, proto_clip.file_length
+                ),
+            ));
+        }
+        // The streams stay locked until the stream has the new region so that
+        // the request the clip makes after the command is handled is read
+        // from the new region.
+        let mut streams = self
+            .audio_streams
+            .lock()
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "audio streams are poisoned"))?;
+        self.commands
+            .push(Command::UpdateAudioClip {
+                id: clip_id,
+                region,
+            })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        if let Some(stream) = streams.get_mut(&clip_id) {
+            stream.set_region(region);
+        }
+        proto_clip.start = region.start;
+        proto_clip.offset = region.offset;
+        proto_clip.length = region.length;
+        Ok(tonic::Response::new(
+            peppermint_proto::UpdateAudioClipResponse {},
+        ))
+    }
+
+    pub fn delete_audio_clip(
+        &mut self,
+        req: tonic::Request<peppermint_proto::DeleteAudioClipRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::DeleteAudioClipResponse>, tonic::Status> {
+        let clip_id = req.get_ref().id;
+        let track = self
+            .audio_clip_to_track
+            .get(&clip_id)
+            .and_then(|track_id| self.tracks.get_mut(track_id))
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("audio clip {} not found", clip_id),
+                )
+            })?;
+        self.commands
+            .push(Command::DeleteAudioClip { id: clip_id })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        track.audio_clips.retain(|c| c.id != clip_id);
+        self.audio_clip_to_track.remove(&clip_id);
+        self.remove_audio_stream(clip_id);
+        self.ids.release_id(clip_id);
+        Ok(tonic::Response::new(
+            peppermint_proto::DeleteAudioClipResponse {},
+        ))
+    }
+
+    pub fn start_recording(
+        &mut self,
+        req: tonic::Request<peppermint_proto::StartRecordingRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::StartRecordingResponse>, tonic::Status> {
+        let source = match req.get_ref().track_id {
+            0 => peppermint_core::recording::RecordingSource::Output,
+            track_id if self.tracks.contains_key(&track_id) => {
+                peppermint_core::recording::RecordingSource::Track(track_id)
+            }
+            track_id => {
+                return Err(tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("track {} not found", track_id),
+                ))
+            }
+        };
+        let path = &req.get_ref().path;
+        let recording_id = self.ids.next_id();
+        let (recorder, recording) = recorder::Recorder::start(
+            recording_id,
+            source,
+            std::path::Path::new(path),
+            self.sample_rate,
+        )
+        .map_err(|e| {
+            self.ids.release_id(recording_id);
+            tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("failed to create {}: {}", path, e),
+            )
+        })?;
+        if self
+            .commands
+            .push(Command::StartRecording(recording))
+            .is_err()
+        {
+            self.ids.release_id(recording_id);
+            let _ = recorder.stop();
+            return Err(tonic::Status::new(
+                tonic::Code::Internal,
+                "failed to send command",
+            ));
+        }
+        self.recorders.insert(recording_id, recorder);
+        Ok(tonic::Response::new(
+            peppermint_proto::StartRecordingResponse { recording_id },
+        ))
+    }
+
+    /// Stop a recording. The returned future finishes the file once the audio
+    /// thread has applied the stop so it should be awaited without holding the
+    /// manager.
+    pub fn stop_recording(
+        &mut self,
+        req: tonic::Request<peppermint_proto::StopRecordingRequest>,
+    ) -> Result<
+        impl std::future::Future<
+            Output = Result<
+                tonic::Response<peppermint_proto::StopRecordingResponse>,
+                tonic::Status,
+            >,
+        >,
+        tonic::Status,
+    > {
+        let recording_id = req.get_ref().recording_id;
+        let recorder = self.recorders.remove(&recording_id).ok_or_else(|| {
+            tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("recording {} not found", recording_id),
+            )
+        })?;
+        if self
+            .commands
+            .push(Command::StopRecording { id: recording_id })
+            .is_err()
+        {
+            self.recorders.insert(recording_id, recorder);
+            return Err(tonic::Status::new(
+                tonic::Code::Internal,
+                "failed to send command",
+            ));
+        }
+        self.ids.release_id(recording_id);
+        let applied = self.wait_for_commands(recorder::STOP_TIMEOUT);
+        Ok(async move {
+            // The frames that were already sent are still written if the audio
+            // thread is late.
+            if let Err(e) = applied.await {
+                warn!("Finishing recording {}: {}", recording_id, e.message());
+            }
+            let (frames, dropped_frames) = tokio::task::spawn_blocking(move || recorder.stop())
+                .await
+                .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Internal,
+                        format!("failed to write recording {}: {}", recording_id, e),
+                    )
+                })?;
+            Ok(tonic::Response::new(
+                peppermint_proto::StopRecordingResponse {
+                    frames,
+                    dropped_frames,
+                },
+            ))
+        })
+    }
+
+    pub fn midi_learn(
+        &mut self,
+        req: tonic::Request<peppermint_proto::MidiLearnRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::MidiLearnResponse>, tonic::Status> {
+        use peppermint_core::midi_learn::MidiTarget;
+        let request = req.get_ref();
+        if !request.min.is_finite() || !request.max.is_finite() {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "min and max must be finite",
+            ));
+        }
+        let (target, min, max) = match (request.plugin_instance_id, request.track_id) {
+            (plugin_instance_id, 0) if plugin_instance_id != 0 => {
+                let port = self.control_port(plugin_instance_id, request.param_index)?;
+                let (min, max) = if request.min == 0.0 && request.max == 0.0 {
+                    (port.min_value.unwrap_or(0.0), port.max_value.unwrap_or(1.0))
+                } else {
+                    (
+                        clamp_to_port(&port, request.min),
+                        clamp_to_port(&port, request.max),
+                    )
+                };
+                let target = MidiTarget::PluginParameter {
+                    instance: plugin_instance_id,
+                    port: port.index,
+                };
+                (target, min, max)
+            }
+            (0, track_id) if track_id != 0 => {
+                if !self.tracks.contains_key(&track_id) {
+                    return Err(tonic::Status::new(
+                        tonic::Code::NotFound,
+                        format!("track {} not found", track_id),
+                    ));
+                }
+                let (property, (low, high)) = track_property_from_proto(request.track_property)
+                    .ok_or_else(|| {
+                        tonic::Status::new(
+                            tonic::Code::InvalidArgument,
+                            "track_property must be set",
+                        )
+                    })?;
+                let (min, max) = if request.min == 0.0 && request.max == 0.0 {
+                    (low, high)
+                } else {
+                    (request.min.clamp(low, high), request.max.clamp(low, high))
+                };
+                (
+                    MidiTarget::TrackProperty {
+                        track: track_id,
+                        property,
+                    },
+                    min,
+                    max,
+                )
+            }
+            _ => {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    "exactly one of plugin_instance_id and track_id must be set",
+                ))
+            }
+        };
+        // Only one binding may be armed at a time.
+        let armed: Vec<_> = self
+            .midi_bindings
+            .iter()
+            .filter(|(_, b)| b.state.control().is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in armed {
+            self.remove_midi_binding(id)?;
+        }
+        let binding_id = self.ids.next_id();
+        let state = Arc::new(peppermint_core::midi_learn::MidiBindingState::default());
+        self.commands
+            .push(Command::CreateMidiBinding(
+                peppermint_core::midi_learn::MidiBinding::new(
+                    binding_id,
+                    target,
+                    min,
+                    max,
+                    None,
+                    state.clone(),
+                ),
+            ))
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        let binding = peppermint_proto::MidiBinding {
+            id: binding_id,
+            plugin_instance_id: request.plugin_instance_id,
+            param_index: if request.plugin_instance_id == 0 {
+                0
+            } else {
+                request.param_index
+            },
+            track_id: request.track_id,
+            track_property: if request.track_id == 0 {
+                0
+            } else {
+                request.track_property
+            },
+            min,
+            max,
+            learned: false,
+            midi_port: 0,
+            channel: 0,
+            controller: 0,
+        };
+        self.midi_bindings.insert(
+            binding_id,
+            MidiBindingEntry {
+                binding,
+                state,
+                applied_value: None,
+            },
+        );
+        Ok(tonic::Response::new(peppermint_proto::MidiLearnResponse {
+            binding_id,
+        }))
+    }
+
+    pub fn get_midi_bindings(
+        &self,
+    ) -> Result<tonic::Response<peppermint_proto::GetMidiBindingsResponse>, tonic::Status> {
+        let mut bindings: Vec<_> = self
+            .midi_bindings
+            .values()
+            .map(|b| b.binding.clone())
+            .collect();
+        bindings.sort_by_key(|b| b.id);
+        Ok(tonic::Response::new(
+            peppermint_proto::GetMidiBindingsResponse { bindings },
+        ))
+    }
+
+    pub fn delete_midi_binding(
+        &mut self,
+        req: tonic::Request<peppermint_proto::DeleteMidiBindingRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::DeleteMidiBindingResponse>, tonic::Status> {
+        let binding_id = req.get_ref().binding_id;
+        if !self.midi_bindings.contains_key(&binding_id) {
+            return Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("midi binding {} not found", binding_id),
+            ));
+        }
+        self.remove_midi_binding(binding_id)?;
+        Ok(tonic::Response::new(
+            peppermint_proto::DeleteMidiBindingResponse {},
+        ))
+    }
+
+    /// Copy the controls that were learned and the values that were set by
+    /// midi bindings on the audio thread into the session.
+    pub fn sync_midi_bindings(&mut self) {
+        let mut before = None;
+        for entry in self.midi_bindings.values_mut() {
+            if let Some(control) = entry.state.control() {
+                entry.binding.learned = true;
+                entry.binding.midi_port = control.port as u32;
+                entry.binding.channel = control.channel as u32 + 1;
+                entry.binding.controller = control.controller as u32;
+            }
+            let value = match entry.state.value() {
+                Some(value) if entry.applied_value != Some(value) => value,
+                _ => continue,
+            };
+            entry.applied_value = Some(value);
+            if before.is_none() {
+                before = Some(self.tracks.clone());
+            }
+            let binding = &entry.binding;
+            if binding.plugin_instance_id != 0 {
+                let plugin_instance = self
+                    .plugin_instance_to_track
+                    .get(&binding.plugin_instance_id)
+                    .and_then(|track_id| self.tracks.get_mut(track_id))
+                    .and_then(|t| {
+                        t.plugin_instances
+                            .iter_mut()
+                            .find(|p| p.id == binding.plugin_instance_id)
+                    });
+                if let Some(param) =
+                    plugin_instance.and_then(|p| p.params.get_mut(binding.param_index as usize))
+                {
+                    *param = value;
+                }
+            } else if let Some(track) = self.tracks.get_mut(&binding.track_id) {
+                use peppermint_proto::track_property_update::TrackProperty;
+                match TrackProperty::from_i32(binding.track_property) {
+                    Some(TrackProperty::Gain) => track.gain = value,
+                    Some(TrackProperty::Pan) => track.pan = value.clamp(-1.0, 1.0),
+                    Some(TrackProperty::Mute) => track.mute = value > 0.5,
+                    Some(TrackProperty::Solo) => track.solo = value > 0.5,
+                    Some(TrackProperty::MidiOutput) => track.midi_output = value > 0.5,
+                    Some(TrackProperty::Undefined) | None => (),
+                }
+            }
+        }
+        if let Some(before) = before {
+            self.publish_changes(&before);
+        }
+    }
+
+    /// Remove all midi bindings that control `track_id`, if given, or any of
+    /// `plugin_instance_ids`.
+    fn remove_midi_bindings_for(
+        &mut self,
+        track_id: Option<peppermint_core::Id>,
+        plugin_instance_ids: &[peppermint_core::Id],
+    ) -> Result<(), tonic::Status> {
+        for id in self.midi_bindings_for(track_id, plugin_instance_ids) {
+            self.remove_midi_binding(id)?;
+        }
+        Ok(())
+    }
+
+    /// The ids of all midi bindings that control `track_id`, if given, or any
+    /// of `plugin_instance_ids`.
+    fn midi_bindings_for(
+        &self,
+        track_id: Option<peppermint_core::Id>,
+        plugin_instance_ids: &[peppermint_core::Id],
+    ) -> Vec<peppermint_core::Id> {
+        self.midi_bindings
+            .values()
+            .filter(|b| {
+                track_id == Some(b.binding.track_id)
+                    || plugin_instance_ids.contains(&b.binding.plugin_instance_id)
+            })
+            .map(|b| b.binding.id)
+            .collect()
+    }
+
+    fn remove_midi_binding(&mut self, id: peppermint_core::Id) -> Result<(), tonic::Status> {
+        self.commands
+            .push(Command::DeleteMidiBinding { id })
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        if let Some(entry) = self.midi_bindings.remove(&id) {
+            if entry.binding.learned {
+                self.removed_midi_bindings.push(entry.binding);
+            }
+        }
+        self.ids.release_id(id);
+        Ok(())
+    }
+
+    /// Get the control input port at `index` of the plugin instance with
+    /// `plugin_instance_id`.
+    fn control_port(
+        &self,
+        plugin_instance_id: peppermint_core::Id,
+        index: u32,
+    ) -> Result<livi::Port, tonic::Status> {
+        let plugin_id = self
+            .plugin_instance_to_track
+            .get(&plugin_instance_id)
+            .and_then(|track_id| self.tracks.get(track_id))
+            .and_then(|t| {
+                t.plugin_instances
+                    .iter()
+                    .find(|p| p.id == plugin_instance_id)
+            })
+            .map(|p| p.plugin_id.clone())
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("plugin instance {} not found", plugin_instance_id),
+                )
+            })?;
+        self.plugin_control_port(&plugin_id, index)
+    }
+
+    /// Get the control input port at `index` of the plugin with `plugin_id`.
+    fn plugin_control_port(
+        &self,
+        plugin_id: &str,
+        index: u32,
+    ) -> Result<livi::Port, tonic::Status> {
+        let plugin = self.plugin_by_id(plugin_id).ok_or_else(|| {
+            tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("plugin {} not found", plugin_id),
+            )
+        })?;
+        let port = plugin
+            .ports_with_type(livi::PortType::ControlInput)
+            .nth(index as usize);
+        port.ok_or_else(|| {
+            tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("param {} out of range for plugin {}", index, plugin_id),
+            )
+        })
+    }
+
+    /// Create a copy of the session with fresh plugin instances that renders
+    /// the requested range.
+    pub fn render(
+        &self,
+        req: tonic::Request<peppermint_proto::RenderRequest>,
+    ) -> Result<render::RenderJob, tonic::Status> {
+        let start_frame = req.get_ref().start_frame;
+        let end_frame = req.get_ref().end_frame;
+        if end_frame <= start_frame {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "end frame {} must be after start frame {}",
+                    end_frame, start_frame
+                ),
+            ));
+        }
+        let session = self.session_diff(&Snapshot::default(), &self.snapshot())?;
+        let mut commands = session.commands;
+        commands.push(Command::Seek(start_frame));
+        // Leave room for the command that starts playback.
+        let (commands_tx, commands_rx) =
+            ringbuf::RingBuffer::<SequencedCommand>::new(commands.len() + 1).split();
+        let mut commands_tx = command_queue::CommandQueue::new(commands_tx);
+        for command in commands {
+            if commands_tx.push(command).is_err() {
+                return Err(tonic::Status::new(
+                    tonic::Code::Internal,
+                    "failed to send command",
+                ));
+            }
+        }
+        let (garbage_tx, garbage_rx) = ringbuf::RingBuffer::<Garbage>::new(1024).split();
+        // The master track is not created with `new_master_track` so that the
+        // render does not replace the meter of the live master track.
+        let master = peppermint_core::track::Track::new(
+            peppermint_core::MASTER_TRACK_ID,
+            self.buffer_size,
+            &self.lv2_features.0,
+        );
+        let core = peppermint_core::PeppermintCore::new(commands_rx, garbage_tx, master);
+        Ok(render::RenderJob {
+            core,
+            commands: commands_tx,
+            garbage: garbage_rx,
+            streams: session.streams.into_iter().map(|(_, s)| s).collect(),
+            path: req.get_ref().path.clone().into(),
+            start_frame,
+            end_frame,
+            sample_rate: self.sample_rate,
+            buffer_size: self.buffer_size,
+        })
+    }
+
+    pub fn save_project(
+        &self,
+        req: tonic::Request<peppermint_proto::SaveProjectRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::SaveProjectResponse>, tonic::Status> {
+        let dir = std::path::Path::new(&req.get_ref().path).parent();
+        let mut tracks: Vec<_> = self.tracks_with_plugin_states(dir).into_values().collect();
+        tracks.sort_by_key(|t| t.id);
+        let mut midi_bindings: Vec<_> = self
+            .midi_bindings
+            .values()
+            .filter(|b| b.binding.learned)
+            .map(|b| b.binding.clone())
+            .collect();
+        midi_bindings.sort_by_key(|b| b.id);
+        let project = peppermint_proto::Project {
+            tracks,
+            pan_law: pan_law_to_proto(self.pan_law) as i32,
+            beats_per_minute: self.tempo.beats_per_minute,
+            beats_per_bar: self.tempo.beats_per_bar,
+            beat_unit: self.tempo.beat_unit,
+            midi_bindings,
+            next_id: self.ids.next_id,
+        };
+        let path = &req.get_ref().path;
+        std::fs::write(path, prost::Message::encode_to_vec(&project)).map_err(|e| {
+            tonic::Status::new(
+                tonic::Code::Internal,
+                format!("failed to write {}: {}", path, e),
+            )
+        })?;
+        Ok(tonic::Response::new(
+            peppermint_proto::SaveProjectResponse {},
+        ))
+    }
+
+    pub fn load_project(
+        &mut self,
+        req: tonic::Request<peppermint_proto::LoadProjectRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::LoadProjectResponse>, tonic::Status> {
+        if !self.recorders.is_empty() {
+            return Err(tonic::Status::new(
+                tonic::Code::FailedPrecondition,
+                "all recordings must be stopped before loading a project",
+            ));
+        }
+        let path = &req.get_ref().path;
+        let bytes = std::fs::read(path).map_err(|e| {
+            tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("failed to read {}: {}", path, e),
+            )
+        })?;
+        let project: peppermint_proto::Project =
+            prost::Message::decode(bytes.as_slice()).map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    format!("{} is not a valid project: {}", path, e),
+                )
+            })?;
+        let ids = project_ids(&project)?;
+        let pan_law = pan_law_from_proto(project.pan_law).unwrap_or_default();
+        if !project.beats_per_minute.is_finite()
+            || project.beats_per_minute <= 0.0
+            || project.beats_per_bar == 0
+            || project.beat_unit == 0
+        {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "project has an invalid tempo",
+            ));
+        }
+        let tempo = peppermint_core::transport::Tempo {
+            beats_per_minute: project.beats_per_minute,
+            beats_per_bar: project.beats_per_bar,
+            beat_unit: project.beat_unit,
+        };
+        let target = Snapshot {
+            tracks: project.tracks.iter().map(|t| (t.id, t.clone())).collect(),
+            midi_bindings: Vec::new(),
+            pan_law,
+            tempo,
+            plugin_state_dir: std::path::Path::new(path).parent().map(|p| p.to_path_buf()),
+        };
+        // The core keeps its pan law and tempo after `ResetSession`.
+        let empty = Snapshot {
+            tracks: HashMap::new(),
+            midi_bindings: Vec::new(),
+            pan_law: self.pan_law,
+            tempo: self.tempo,
+            plugin_state_dir: None,
+        };
+        let session = self.session_diff(&empty, &target)?;
+        let mut midi_bindings = HashMap::new();
+        let mut binding_commands = Vec::new();
+        for binding in project.midi_bindings.iter().filter(|b| b.learned) {
+            let (target, control) = self.midi_binding_from_proto(&project.tracks, binding)?;
+            let state = Arc::new(peppermint_core::midi_learn::MidiBindingState::default());
+            binding_commands.push(Command::CreateMidiBinding(
+                peppermint_core::midi_learn::MidiBinding::new(
+                    binding.id,
+                    target,
+                    binding.min,
+                    binding.max,
+                    Some(control),
+                    state.clone(),
+                ),
+            ));
+            midi_bindings.insert(
+                binding.id,
+                MidiBindingEntry {
+                    binding: binding.clone(),
+                    state,
+                    applied_value: None,
+                },
+            );
+        }
+        // Stop, Seek, and ResetSession are sent before the session and the
+        // batch starts with `Command::BeginBatch`.
+        if self.commands.remaining() < session.commands.len() + binding_commands.len() + 4 {
+            return Err(tonic::Status::new(
+                tonic::Code::ResourceExhausted,
+                "project is too large for the command queue",
+            ));
+        }
+
+        if let Ok(mut meters) = self.meters.lock() {
+            meters.clear();
+        }
+        let master = self.new_master_track();
+        if let Ok(mut meters) = self.meters.lock() {
+            meters.extend(session.meters);
+        }
+        let commands = [
+            Command::Stop,
+            Command::Seek(0),
+            Command::ResetSession(master),
+        ]
+        .into_iter()
+        .chain(session.commands)
+        .chain(binding_commands);
+        // The project replaces the session in a single process cycle.
+        self.commands.begin_batch();
+        for command in commands {
+            self.commands
+                .push(command)
+                .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        }
+        self.commands
+            .send_batch()
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        if let Ok(mut streams) = self.audio_streams.lock() {
+            streams.clear();
+            streams.extend(session.streams);
+        }
+        let mut tracks = target.tracks;
+        clear_plugin_states(&mut tracks);
+        let before = std::mem::replace(&mut self.tracks, tracks);
+        self.index_tracks();
+        self.plugin_states = session.plugin_states.into_iter().collect();
+        self.publish_changes(&before);
+        self.midi_bindings = midi_bindings;
+        self.pan_law = pan_law;
+        self.tempo = tempo;
+        self.ids = ids;
+        self.history.clear();
+        Ok(tonic::Response::new(
+            peppermint_proto::LoadProjectResponse {},
+        ))
+    }
+
+    pub fn apply_batch(
+        &mut self,
+        req: tonic::Request<peppermint_proto::ApplyBatchRequest>,
+    ) -> Result<tonic::Response<peppermint_proto::ApplyBatchResponse>, tonic::Status> {
+        let tracks = self.tracks.clone();
+        let ids = self.ids.clone();
+        self.commands.begin_batch();
+        let results: Result<Vec<_>, _> = req
+            .into_inner()
+            .operations
+            .into_iter()
+            .enumerate()
+            .map(|(index, operation)| {
+                self.apply_batch_operation(operation).map_err(|e| {
+                    tonic::Status::new(e.code(), format!("operation {}: {}", index, e.message()))
+                })
+            })
+            .collect();
+        let results = results.and_then(|results| {
+            self.commands.send_batch().map_err(|_| {
+                tonic::Status::new(
+                    tonic::Code::ResourceExhausted,
+                    "batch is too large for the command queue",
+                )
+            })?;
+            Ok(results)
+        });
+        match results {
+            Ok(results) => Ok(tonic::Response::new(peppermint_proto::ApplyBatchResponse {
+                results,
+            })),
+            Err(e) => {
+                // Nothing was sent to the core so only the manager is rolled
+                // back.
+                self.commands.discard_batch();
+                self.tracks = tracks;
+                self.ids = ids;
+                self.index_tracks();
+                self.prune_plugin_states();
+                if let Ok(mut meters) = self.meters.lock() {
+                    meters.retain(|id, _| self.tracks.contains_key(id));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_batch_operation(
+        &mut self,
+        operation: peppermint_proto::BatchOperation,
+    ) -> Result<peppermint_proto::BatchOperationResult, tonic::Status> {
+        let mut result = peppermint_proto::BatchOperationResult::default();
+        match operation {
+            peppermint_proto::BatchOperation {
+                create_track: Some(req),
+                instantiate_plugin: None,
+                update_plugin_instance: None,
+                update_track: None,
+            } => {
+                let res = self.create_track(tonic::Request::new(req))?;
+                result.create_track = Some(res.into_inner());
+            }
+            peppermint_proto::BatchOperation {
+                create_track: None,
+                instantiate_plugin: Some(req),
+                update_plugin_instance: None,
+                update_track: None,
+            } => {
+                let res = self.instantiate_plugin(tonic::Request::new(req))?;
+                result.instantiate_plugin = Some(res.into_inner());
+            }
+            peppermint_proto::BatchOperation {
+                create_track: None,
+                instantiate_plugin: None,
+                update_plugin_instance: Some(req),
+                update_track: None,
+            } => {
+                self.update_plugin_instance(tonic::Request::new(req))?;
+            }
+            peppermint_proto::BatchOperation {
+                create_track: None,
+                instantiate_plugin: None,
+                update_plugin_instance: None,
+                update_track: Some(req),
+            } => {
+                self.update_track(tonic::Request::new(req))?;
+            }
+            _ => {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    "exactly one operation must be set",
+                ))
+            }
+        }
+        Ok(result)
+    }
+
+    /// Run `f` and record the change that undoes it in the history if it
+    /// succeeds.
+    pub fn with_history<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, tonic::Status>,
+    ) -> Result<T, tonic::Status> {
+        let before = self.tracks.clone();
+        let (pan_law, tempo) = (self.pan_law, self.tempo);
+        self.deleted_plugin_states.clear();
+        self.removed_midi_bindings.clear();
+        let result = f(self)?;
+        self.publish_changes(&before);
+        let change = self.inverse_change(&before, pan_law, tempo);
+        self.history.record(change);
+        Ok(result)
+    }
+
+    pub fn undo(
+        &mut self,
+    ) -> Result<tonic::Response<peppermint_proto::UndoResponse>, tonic::Status> {
+        let change = self.history.pop_undo().ok_or_else(|| {
+            tonic::Status::new(tonic::Code::FailedPrecondition, "nothing to undo")
+        })?;
+        match self.apply_change(&change) {
+            Ok(inverse) => self.history.push_redo(inverse),
+            Err(e) => {
+                self.history.push_undo(change);
+                return Err(e);
+            }
+        }
+        Ok(tonic::Response::new(peppermint_proto::UndoResponse {}))
+    }
+
+    pub fn redo(
+        &mut self,
+    ) -> Result<tonic::Response<peppermint_proto::RedoResponse>, tonic::Status> {
+        let change = self.history.pop_redo().ok_or_else(|| {
+            tonic::Status::new(tonic::Code::FailedPrecondition, "nothing to redo")
+        })?;
+        match self.apply_change(&change) {
+            Ok(inverse) => self.history.push_undo(inverse),
+            Err(e) => {
+                self.history.push_redo(change);
+                return Err(e);
+            }
+        }
+        Ok(tonic::Response::new(peppermint_proto::RedoResponse {}))
+    }
+
+    /// Apply `change` to the session and return the change that reverts it.
+    fn apply_change(&mut self, change: &Change) -> Result<Change, tonic::Status> {
+        let before = self.tracks.clone();
+        let (pan_law, tempo) = (self.pan_law, self.tempo);
+        let mut target = Snapshot {
+            tracks: before.clone(),
+            midi_bindings: change.midi_bindings.clone(),
+            pan_law: change.pan_law,
+            tempo: change.tempo,
+            plugin_state_dir: None,
+        };
+        history::apply_entries(&mut target.tracks, change.tracks.iter().cloned());
+        self.deleted_plugin_states.clear();
+        self.removed_midi_bindings.clear();
+        self.restore(&target)?;
+        self.publish_changes(&before);
+        Ok(self.inverse_change(&before, pan_law, tempo))
+    }
+
+    /// The change that reverts the session from its current state to
+    /// `before`, `pan_law`, and `tempo`.
+    fn inverse_change(
+        &mut self,
+        before: &HashMap<peppermint_core::Id, peppermint_proto::Track>,
+        pan_law: peppermint_core::pan::PanLaw,
+        tempo: peppermint_core::transport::Tempo,
+    ) -> Change {
+        let mut tracks = history::changed_entries(before, &self.tracks);
+        for plugin_instance in tracks
+            .iter_mut()
+            .filter_map(|(_, track)| track.as_mut())
+            .flat_map(|track| track.plugin_instances.iter_mut())
+        {
+            if let Some(state) = self.deleted_plugin_states.remove(&plugin_instance.id) {
+                plugin_instance.state = state;
+            }
+        }
+        Change {
+            tracks,
+            midi_bindings: std::mem::take(&mut self.removed_midi_bindings),
+            pan_law,
+            tempo,
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tracks: self.tracks_with_plugin_states(None),
+            midi_bindings: self
+                .midi_bindings
+                .values()
+                .filter(|b| b.binding.learned)
+                .map(|b| b.binding.clone())
+                .collect(),
+            pan_law: self.pan_law,
+            tempo: self.tempo,
+            plugin_state_dir: None,
+        }
+    }
+
+    /// A copy of the tracks where plugin instances include their LV2 state.
+    /// Paths within `dir` are stored relative to it.
+    fn tracks_with_plugin_states(
+        &self,
+        dir: Option<&std::path::Path>,
+    ) -> HashMap<peppermint_core::Id, peppermint_proto::Track> {
+        let mut tracks = self.tracks.clone();
+        for plugin_instance in tracks
+            .values_mut()
+            .flat_map(|t| t.plugin_instances.iter_mut())
+        {
+            if let Some(state) = self.save_plugin_state(plugin_instance.id, dir) {
+                plugin_instance.state = state;
+            }
+        }
+        tracks
+    }
+
+    /// Save the LV2 state of the plugin instance with `id`. Returns `None` if
+    /// the instance does not support the State extension or saving fails.
+    fn save_plugin_state(
+        &self,
+        id: peppermint_core::Id,
+        dir: Option<&std::path::Path>,
+    ) -> Option<Vec<u8>> {
+        let handle = self.plugin_states.get(&id)?;
+        // Handles are removed when their instance is deleted so the instance
+        // is still alive.
+        match unsafe { handle.save(&self.lv2_features.0, dir) } {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!("Failed to save the state of plugin instance {}: {}", id, e);
+                None
+            }
+        }
+    }
+
+    /// Keep the LV2 state of the plugin instance with `id` for the history.
+    /// This must be called before the command that deletes the instance is
+    /// sent.
+    fn save_deleted_plugin_state(&mut self, id: peppermint_core::Id) {
+        if let Some(state) = self.save_plugin_state(id, None) {
+            self.deleted_plugin_states.insert(id, state);
+        }
+    }
+
+    /// Forget the state handles of plugin instances that are no longer in the
+    /// session.
+    fn prune_plugin_states(&mut self) {
+        let plugin_instance_to_track = &self.plugin_instance_to_track;
+        self.plugin_states
+            .retain(|id, _| plugin_instance_to_track.contains_key(id));
+    }
+
+    /// Change the session to `target` by sending only the commands for what
+    /// differs.
+    fn restore(&mut self, target: &Snapshot) -> Result<(), tonic::Status> {
+        let current = Snapshot {
+            tracks: self.tracks.clone(),
+            midi_bindings: Vec::new(),
+            pan_law: self.pan_law,
+            tempo: self.tempo,
+            plugin_state_dir: None,
+        };
+        let session = self.session_diff(&current, target)?;
+        // Midi bindings follow the tracks and plugin instances that they
+        // control. Bindings of objects that exist before and after are kept.
+        let removed_bindings: Vec<_> = self
+            .midi_bindings
+            .values()
+            .filter(|b| !binding_target_exists(&b.binding, &target.tracks))
+            .map(|b| b.binding.id)
+            .collect();
+        let restored_bindings: Vec<_> = target
+            .midi_bindings
+            .iter()
+            .filter(|b| {
+                !binding_target_exists(b, &current.tracks)
+                    && !self.midi_bindings.contains_key(&b.id)
+            })
+            .collect();
+        let mut binding_commands = Vec::with_capacity(restored_bindings.len());
+        let mut binding_entries = Vec::with_capacity(restored_bindings.len());
+        if !restored_bindings.is_empty() {
+            let target_tracks: Vec<_> = target.tracks.values().cloned().collect();
+            for binding in restored_bindings {
+                let (binding_target, control) =
+                    self.midi_binding_from_proto(&target_tracks, binding)?;
+                let state = Arc::new(peppermint_core::midi_learn::MidiBindingState::default());
+                binding_commands.push(Command::CreateMidiBinding(
+                    peppermint_core::midi_learn::MidiBinding::new(
+                        binding.id,
+                        binding_target,
+                        binding.min,
+                        binding.max,
+                        Some(control),
+                        state.clone(),
+                    ),
+                ));
+                binding_entries.push(MidiBindingEntry {
+                    binding: binding.clone(),
+                    state,
+                    applied_value: None,
+                });
+            }
+        }
+        // The batch starts with `Command::BeginBatch`.
+        if self.commands.remaining()
+            < session.commands.len() + removed_bindings.len() + binding_commands.len() + 1
+        {
+            return Err(tonic::Status::new(
+                tonic::Code::ResourceExhausted,
+                "too many changes for the command queue",
+            ));
+        }
+        // All changes are applied in the same process cycle.
+        self.commands.begin_batch();
+        for command in session.commands {
+            self.commands
+                .push(command)
+                .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        }
+        for id in removed_bindings {
+            self.remove_midi_binding(id)?;
+        }
+        for command in binding_commands {
+            self.commands
+                .push(command)
+                .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        }
+        // Deleted instances may be deallocated as soon as the batch is sent.
+        let target_instances = by_id(&target.tracks, |t| &t.plugin_instances, |p| p.id);
+        for id in by_id(&current.tracks, |t| &t.plugin_instances, |p| p.id).into_keys() {
+            if !target_instances.contains_key(&id) {
+                self.save_deleted_plugin_state(id);
+            }
+        }
+        // The streams stay locked until they have their new regions, see
+        // `update_audio_clip`.
+        let audio_streams = self.audio_streams.clone();
+        let streams = audio_streams.lock();
+        self.commands
+            .send_batch()
+            .map_err(|_| tonic::Status::new(tonic::Code::Internal, "failed to send command"))?;
+        if let Ok(mut meters) = self.meters.lock() {
+            meters.retain(|id, _| target.tracks.contains_key(id));
+            meters.extend(session.meters);
+        }
+        let target_audio_clips = by_id(&target.tracks, |t| &t.audio_clips, |c| c.id);
+        if let Ok(mut streams) = streams {
+            streams.retain(|id, _| target_audio_clips.contains_key(id));
+            streams.extend(session.streams);
+            for (id, region) in session.audio_clip_regions {
+                if let Some(stream) = streams.get_mut(&id) {
+                    stream.set_region(region);
+                }
+            }
+        }
+        for id in session_object_ids(current.tracks.values()) {
+            self.ids.release_id(id);
+        }
+        for id in session_object_ids(target.tracks.values()) {
+            self.ids.register_id(id);
+        }
+        for entry in binding_entries {
+            self.ids.register_id(entry.binding.id);
+            self.midi_bindings.insert(entry.binding.id, entry);
+        }
+        self.tracks = target.tracks.clone();
+        clear_plugin_states(&mut self.tracks);
+        self.index_tracks();
+        self.prune_plugin_states();
+        self.plugin_states.extend(session.plugin_states);
+        self.pan_law = target.pan_law;
+        self.tempo = target.tempo;
+        Ok(())
+    }
+
+    /// Rebuild the maps from plugin instances, sends, and clips to the tracks
+    /// that own them.
+    fn index_tracks(&mut self) {
+        self.plugin_instance_to_track.clear();
+        self.send_to_track.clear();
+        self.midi_clip_to_track.clear();
+        self.audio_clip_to_track.clear();
+        for track in self.tracks.values() {
+            for plugin_instance in track.plugin_instances.iter() {
+                self.plugin_instance_to_track
+                    .insert(plugin_instance.id, track.id);
+            }
+            for send in track.sends.iter() {
+                self.send_to_track.insert(send.id, track.id);
+            }
+            for clip in track.midi_clips.iter() {
+                self.midi_clip_to_track.insert(clip.id, track.id);
+            }
+            for clip in track.audio_clips.iter() {
+                self.audio_clip_to_track.insert(clip.id, track.id);
+            }
+        }
+    }
+
+    /// Create the commands that change a core from the `current` session to
+    /// the `target` session. A core that only has an empty master track is
+    /// the same as a `current` session with no tracks.
+    fn session_diff(
+        &self,
+        current: &Snapshot,
+        target: &Snapshot,
+    ) -> Result<Session, tonic::Status> {
+        check_session_capacity(current, target)?;
+        let mut session = Session::default();
+        if current.pan_law != target.pan_law {
+            session.commands.push(Command::SetPanLaw(target.pan_law));
+        }
+        if current.tempo != target.tempo {
+            session.commands.push(Command::SetTempo(target.tempo));
+        }
+        let mut target_tracks: Vec<_> = target.tracks.values().collect();
+        target_tracks.sort_by_key(|t| t.id);
+        for track in target_tracks.iter() {
+            let current_track = current.tracks.get(&track.id);
+            if current_track.is_none() && track.id != peppermint_core::MASTER_TRACK_ID {
+                let core_track = peppermint_core::track::Track::new(
+                    track.id,
+                    self.buffer_size,
+                    &self.lv2_features.0,
+                );
+                session.meters.push((track.id, core_track.meter()));
+                session.commands.push(Command::CreateTrack(core_track));
+            }
+            push_track_settings(&mut session.commands, current_track, track)?;
+        }
+
+        let current_instances = by_id(&current.tracks, |t| &t.plugin_instances, |p| p.id);
+        let target_instances = by_id(&target.tracks, |t| &t.plugin_instances, |p| p.id);
+        for id in current_instances.keys() {
+            if !target_instances.contains_key(id) {
+                session
+                    .commands
+                    .push(Command::DeletePluginInstance { id: *id });
+            }
+        }
+        for track in target_tracks.iter() {
+            for plugin_instance in track.plugin_instances.iter() {
+                match current_instances.get(&plugin_instance.id) {
+                    Some((_, current_instance)) => self.push_plugin_instance_changes(
+                        &mut session.commands,
+                        current_instance,
+                        plugin_instance,
+                    )?,
+                    None => self.push_new_plugin_instance(
+                        &mut session,
+                        target.plugin_state_dir.as_deref(),
+                        track.id,
+                        plugin_instance,
+                    )?,
+                }
+            }
+            // The order after removing instances that are gone or that moved
+            // away and appending the new instances.
+            let expected_order: Vec<_> = current
+                .tracks
+                .get(&track.id)
+                .into_iter()
+                .flat_map(|t| t.plugin_instances.iter())
+                .filter(|p| target_instances.get(&p.id).map(|(t, _)| *t) == Some(track.id))
+                .chain(
+                    track
+                        .plugin_instances
+                        .iter()
+                        .filter(|p| !current_instances.contains_key(&p.id)),
+                )
+                .map(|p| p.id)
+                .collect();
+            let target_order: Vec<_> = track.plugin_instances.iter().map(|p| p.id).collect();
+            if expected_order != target_order {
+                for (index, plugin_instance) in track.plugin_instances.iter().enumerate() {
+                    session.commands.push(Command::MovePluginInstance {
+                        id: plugin_instance.id,
+                        track: track.id,
+                        index,
+                    });
+                }
+            }
+        }
+
+        let current_sends = by_id(&current.tracks, |t| &t.sends, |s| s.id);
+        let target_sends = by_id(&target.tracks, |t| &t.sends, |s| s.id);
+        for id in current_sends.keys() {
+            if !target_sends.contains_key(id) {
+                session.commands.push(Command::DeleteSend { id: *id });
+            }
+        }
+        for (id, (track_id, send)) in target_sends.iter() {
+            validate_send_level(send.level)?;
+            match current_sends.get(id) {
+                None => session.commands.push(Command::CreateSend {
+                    track: *track_id,
+                    send: peppermint_core::track::AuxSend {
+                        id: send.id,
+                        destination: send.destination_track_id,
+                        level: send.level,
+                        pre_fader: send.pre_fader,
+                    },
+                }),
+                Some((_, current_send)) if current_send != send => {
+                    session.commands.push(Command::UpdateSend {
+                        id: send.id,
+                        level: send.level,
+                        pre_fader: send.pre_fader,
+                    })
+                }
+                Some(_) => (),
+            }
+        }
+
+        let current_midi_clips = by_id(&current.tracks, |t| &t.midi_clips, |c| c.id);
+        let target_midi_clips = by_id(&target.tracks, |t| &t.midi_clips, |c| c.id);
+        for id in current_midi_clips.keys() {
+            if !target_midi_clips.contains_key(id) {
+                session.commands.push(Command::DeleteMidiClip { id: *id });
+            }
+        }
+        for (id, (track_id, clip)) in target_midi_clips.iter() {
+            if current_midi_clips.get(id).map(|(_, c)| c) != Some(clip) {
+                session.commands.push(Command::SetMidiClip {
+                    track: *track_id,
+                    clip: midi_clip_from_proto(clip)?,
+                });
+            }
+        }
+
+        let current_audio_clips = by_id(&current.tracks, |t| &t.audio_clips, |c| c.id);
+        let target_audio_clips = by_id(&target.tracks, |t| &t.audio_clips, |c| c.id);
+        for id in current_audio_clips.keys() {
+            if !target_audio_clips.contains_key(id) {
+                session.commands.push(Command::DeleteAudioClip { id: *id });
+            }
+        }
+        for (id, (track_id, clip)) in target_audio_clips.iter() {
+            let region = peppermint_core::audio_clip::AudioClipRegion {
+                start: clip.start,
+                offset: clip.offset,
+                length: clip.length,
+            };
+            match current_audio_clips.get(id) {
+                None => self.push_new_audio_clip(&mut session, *track_id, clip, region)?,
+                Some((_, current_clip)) if current_clip != clip => {
+                    session.commands.push(Command::UpdateAudioClip {
+                        id: clip.id,
+                        region,
+                    });
+                    session.audio_clip_regions.push((clip.id, region));
+                }
+                Some(_) => (),
+            }
+        }
+
+        for track_id in current.tracks.keys() {
+            if !target.tracks.contains_key(track_id)
+                && *track_id != peppermint_core::MASTER_TRACK_ID
+            {
+                session.commands.push(Command::DeleteTrack(*track_id));
+            }
+        }
+        Ok(session)
+    }
+
+    fn push_new_plugin_instance(
+        &self,
+        session: &mut Session,
+        plugin_state_dir: Option<&std::path::Path>,
+        track_id: peppermint_core::Id,
+        plugin_instance: &peppermint_proto::PluginInstance,
+    ) -> Result<(), tonic::Status> {
+        let plugin = self
+            .plugin_by_id(&plugin_instance.plugin_id)
+            .ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("plugin {} not found", plugin_instance.plugin_id),
+                )
+            })?;
+        let mut instance = Box::new(unsafe {
+            plugin
+                .instantiate(self.lv2_features.0.clone(), self.sample_rate)
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Internal,
+                        format!("failed to instantiate plugin: {:?}", e),
+                    )
+                })?
+        });
+        plugin_state::restore(
+            &mut instance,
+            &self.lv2_features.0,
+            plugin_state_dir,
+            &plugin_instance.state,
+        )
+        .map_err(|e| {
+            tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "failed to restore the state of plugin instance {}: {}",
+                    plugin_instance.id, e
+                ),
+            )
+        })?;
+        if let Some(state) = plugin_state::StateHandle::new(&instance) {
+            session.plugin_states.push((plugin_instance.id, state));
+        }
+        let commands = &mut session.commands;
+        commands.push(Command::PushPluginInstance {
+            id: plugin_instance.id,
+            track: track_id,
+            instance,
+        });
+        for (port, value) in plugin
+            .ports_with_type(livi::PortType::ControlInput)
+            .zip(plugin_instance.params.iter())
+        {
+            commands.push(Command::UpdatePluginInstance {
+                id: plugin_instance.id,
+                port: port.index,
+                value: *value,
+            });
+        }
+        push_plugin_instance_properties(commands, plugin_instance);
+        Ok(())
+    }
+
+    fn push_plugin_instance_changes(
+        &self,
+        commands: &mut Vec<Command>,
+        current: &peppermint_proto::PluginInstance,
+        target: &peppermint_proto::PluginInstance,
+    ) -> Result<(), tonic::Status> {
+        if current.params != target.params {
+            let plugin = self.plugin_by_id(&target.plugin_id).ok_or_else(|| {
+                tonic::Status::new(
+                    tonic::Code::NotFound,
+                    format!("plugin {} not found", target.plugin_id),
+                )
+            })?;
+            for (port, (current_value, value)) in plugin
+                .ports_with_type(livi::PortType::ControlInput)
+                .zip(current.params.iter().zip(target.params.iter()))
+            {
+                if current_value != value {
+                    commands.push(Command::UpdatePluginInstance {
+                        id: target.id,
+                        port: port.index,
+                        value: *value,
+                    });
+                }
+            }
+        }
+        if current.bypassed != target.bypassed || current.mix != target.mix {
+            push_plugin_instance_properties(commands, target);
+        }
+        Ok(())
+    }
+
+    fn push_new_audio_clip(
+        &self,
+        session: &mut Session,
+        track_id: peppermint_core::Id,
+        clip: &peppermint_proto::AudioClip,
+        region: peppermint_core::audio_clip::AudioClipRegion,
+    ) -> Result<(), tonic::Status> {
+        let decoder = streamer::Decoder::open(std::path::Path::new(&clip.path)).map_err(|e| {
+            tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("failed to open {}: {}", clip.path, e),
+            )
+        })?;
+        if decoder.sample_rate() != self.sample_rate {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "{} has a sample rate of {} but {} is required",
+                    clip.path,
+                    decoder.sample_rate(),
+                    self.sample_rate
+                ),
+            ));
+        }
+        let (stream, audio_clip) = streamer::Stream::new(clip.id, decoder, region);
+        session.streams.push((clip.id, stream));
+        session.commands.push(Command::CreateAudioClip {
+            track: track_id,
+            clip: audio_clip,
+        });
+        Ok(())
+    }
+
+    /// Get the target and control of `binding` from a saved project with
+    /// `tracks`.
+    fn midi_binding_from_proto(
+        &self,
+        tracks: &[peppermint_proto::Track],
+        binding: &peppermint_proto::MidiBinding,
+    ) -> Result<
+        (
+            peppermint_core::midi_learn::MidiTarget,
+            peppermint_core::midi_learn::MidiControl,
+        ),
+        tonic::Status,
+    > {
+        use peppermint_core::midi_learn::MidiTarget;
+        let invalid = || {
+            tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("midi binding {} is not valid", binding.id),
+            )
+        };
+        if !(1..=16).contains(&binding.channel) || binding.controller > 127 {
+            return Err(invalid());
+        }
+        let control = peppermint_core::midi_learn::MidiControl {
+            port: binding.midi_port as usize,
+            channel: binding.channel as u8 - 1,
+            controller: binding.controller as u8,
+        };
+        let target = if binding.plugin_instance_id != 0 {
+            let plugin_id = tracks
+                .iter()
+                .flat_map(|t| t.plugin_instances.iter())
+                .find(|p| p.id == binding.plugin_instance_id)
+                .map(|p| p.plugin_id.as_str())
+                .ok_or_else(invalid)?;
+            let port = self.plugin_control_port(plugin_id, binding.param_index)?;
+            MidiTarget::PluginParameter {
+                instance: binding.plugin_instance_id,
+                port: port.index,
+            }
+        } else {
+            if !tracks.iter().any(|t| t.id == binding.track_id) {
+                return Err(invalid());
+            }
+            let (property, _) =
+                track_property_from_proto(binding.track_property).ok_or_else(invalid)?;
+            MidiTarget::TrackProperty {
+                track: binding.track_id,
+                property,
+            }
+        };
+        Ok((target, control))
+    }
+
+    fn remove_audio_stream(&self, clip_id: peppermint_core::Id) {
+        if let Ok(mut streams) = self.audio_streams.lock() {
+            streams.remove(&clip_id);
+        }
+    }
+
+    /// Returns true if audio from `source` reaches `destination` through a
+    /// chain of sends, or if they are the same track.
+    fn track_feeds_into(
+        &self,
+        source: peppermint_core::Id,
+        destination: peppermint_core::Id,
+    ) -> bool {
+        let mut visited = HashSet::new();
+        let mut to_visit = vec![source];
+        while let Some(track_id) = to_visit.pop() {
+            if track_id == destination {
+                return true;
+            }
+            if !visited.insert(track_id) {
+                continue;
+            }
+            if let Some(track) = self.tracks.get(&track_id) {
+                to_visit.extend(track.sends.iter().map(|s| s.destination_track_id));
+            }
+        }
+        false
+    }
+}
+
+#[derive(Clone)]
+pub struct IdManager {
+    next_id: peppermint_core::Id,
+    all_ids: HashSet<peppermint_core::Id>,
+}
+
+impl IdManager {
+    pub fn new() -> IdManager {
+        IdManager {
+            next_id: 1,
+            all_ids: HashSet::new(),
+        }
+    }
+
+    pub fn next_id(&mut self) -> peppermint_core::Id {
+        while self.all_ids.contains(&self.next_id) {
+            self.next_id += 1;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.all_ids.insert(id);
+        id
+    }
+
+    pub fn register_id(&mut self, id: peppermint_core::Id) -> Option<peppermint_core::Id> {
+        if self.all_ids.contains(&id) {
+            None
+        } else {
+            self.all_ids.insert(id);
+            Some(id)
+        }
+    }
+
+    pub fn release_id(&mut self, id: peppermint_core::Id) {
+        self.all_ids.remove(&id);
+    }
+}
+
+impl Default for IdManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns true if the track or plugin instance that `binding` controls is in
+/// `tracks`.
+fn binding_target_exists(
+    binding: &peppermint_proto::MidiBinding,
+    tracks: &HashMap<peppermint_core::Id, peppermint_proto::Track>,
+) -> bool {
+    if binding.plugin_instance_id != 0 {
+        tracks
+            .values()
+            .flat_map(|t| t.plugin_instances.iter())
+            .any(|p| p.id == binding.plugin_instance_id)
+    } else {
+        tracks.contains_key(&binding.track_id)
+    }
+}
+
+/// Remove the LV2 state from all plugin instances in `tracks`. The state is only
+/// kept in the history and in projects.
+fn clear_plugin_states(tracks: &mut HashMap<peppermint_core::Id, peppermint_proto::Track>) {
+    for plugin_instance in tracks
+        .values_mut()
+        .flat_map(|t| t.plugin_instances.iter_mut())
+    {
+        plugin_instance.state.clear();
+    }
+}
+
+fn lv2_plugin_id(p: &livi::Plugin) -> String {
+    format!("lv2{}", p.uri())
+}
+
+/// Convert a proto track property to the core property and its range.
+fn track_property_from_proto(
+    property: i32,
+) -> Option<(peppermint_core::track::TrackProperty, (f32, f32))> {
+    use peppermint_core::track::TrackProperty;
+    match peppermint_proto::track_property_update::TrackProperty::from_i32(property)? {
+        peppermint_proto::track_property_update::TrackProperty::Undefined => None,
+        peppermint_proto::track_property_update::TrackProperty::Gain => {
+            Some((TrackProperty::Gain, (0.0, 1.0)))
+        }
+        peppermint_proto::track_property_update::TrackProperty::Pan => {
+            Some((TrackProperty::Pan, (-1.0, 1.0)))
+        }
+        peppermint_proto::track_property_update::TrackProperty::Mute => {
+            Some((TrackProperty::Mute, (0.0, 1.0)))
+        }
+        peppermint_proto::track_property_update::TrackProperty::Solo => {
+            Some((TrackProperty::Solo, (0.0, 1.0)))
+        }
+        peppermint_proto::track_property_update::TrackProperty::MidiOutput => {
+            Some((TrackProperty::MidiOutput, (0.0, 1.0)))
+        }
+    }
+}
+
+fn pan_law_from_proto(pan_law: i32) -> Option<peppermint_core::pan::PanLaw> {
+    use peppermint_proto::set_pan_law_request::PanLaw;
+    match PanLaw::from_i32(pan_law)? {
+        PanLaw::Undefined => None,
+        PanLaw::ConstantPower => Some(peppermint_core::pan::PanLaw::ConstantPower),
+        PanLaw::Compromise => Some(peppermint_core::pan::PanLaw::Compromise),
+        PanLaw::Linear => Some(peppermint_core::pan::PanLaw::Linear),
+    }
+}
+
+fn pan_law_to_proto(
+    pan_law: peppermint_core::pan::PanLaw,
+) -> peppermint_proto::set_pan_law_request::PanLaw {
+    use peppermint_proto::set_pan_law_request::PanLaw;
+    match pan_law {
+        peppermint_core::pan::PanLaw::ConstantPower => PanLaw::ConstantPower,
+        peppermint_core::pan::PanLaw::Compromise => PanLaw::Compromise,
+        peppermint_core::pan::PanLaw::Linear => PanLaw::Linear,
+    }
+}
+
+/// Returns the ids that are used by `project`. Fails if an id is used more
+/// than once or if a send or binding refers to a track that does not exist.
+fn project_ids(project: &peppermint_proto::Project) -> Result<IdManager, tonic::Status> {
+    let mut ids = IdManager::new();
+    let track_ids: HashSet<_> = project.tracks.iter().map(|t| t.id).collect();
+    if !track_ids.contains(&peppermint_core::MASTER_TRACK_ID) {
+        return Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "project does not have a master track",
+        ));
+    }
+    let mut all_ids = session_object_ids(&project.tracks);
+    for track in project.tracks.iter() {
+        for send in track.sends.iter() {
+            if !track_ids.contains(&send.destination_track_id) {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    format!(
+                        "send {} refers to track {} which does not exist",
+                        send.id, send.destination_track_id
+                    ),
+                ));
+            }
+        }
+    }
+    all_ids.extend(project.midi_bindings.iter().map(|b| b.id));
+    for id in all_ids {
+        if id == 0 || ids.register_id(id).is_none() {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("id {} is not valid or is used more than once", id),
+            ));
+        }
+    }
+    let max_id = ids.all_ids.iter().copied().max().unwrap_or(0);
+    ids.next_id = project.next_id.max(max_id + 1);
+    Ok(ids)
+}
+
+/// The ids of all tracks and the objects that they own.
+fn session_object_ids<'a>(
+    tracks: impl IntoIterator<Item = &'a peppermint_proto::Track>,
+) -> Vec<peppermint_core::Id> {
+    let mut ids = Vec::new();
+    for track in tracks {
+        ids.push(track.id);
+        ids.extend(track.plugin_instances.iter().map(|p| p.id));
+        ids.extend(track.sends.iter().map(|s| s.id));
+        ids.extend(track.midi_clips.iter().map(|c| c.id));
+        ids.extend(track.audio_clips.iter().map(|c| c.id));
+    }
+    ids
+}
+
+/// Map the id of each item in `tracks` to the id of its track and the item.
+fn by_id<'a, T>(
+    tracks: &'a HashMap<peppermint_core::Id, peppermint_proto::Track>,
+    items: impl Fn(&'a peppermint_proto::Track) -> &'a Vec<T>,
+    id: impl Fn(&T) -> peppermint_core::Id,
+) -> HashMap<peppermint_core::Id, (peppermint_core::Id, &'a T)> {
+    tracks
+        .values()
+        .flat_map(|track| items(track).iter().map(move |item| (track.id, item)))
+        .map(|(track_id, item)| (id(item), (track_id, item)))
+        .collect()
+}
+
+/// Push the commands that change the properties and inputs of `current` to
+/// match `target`. If `current` is `None`, all of them are pushed.
+fn push_track_settings(
+    commands: &mut Vec<Command>,
+    current: Option<&peppermint_proto::Track>,
+    target: &peppermint_proto::Track,
+) -> Result<(), tonic::Status> {
+    use peppermint_core::track::TrackProperty;
+    let properties = |t: &peppermint_proto::Track| {
+        [
+            (TrackProperty::Gain, t.gain),
+            (TrackProperty::Pan, t.pan),
+            (TrackProperty::Mute, bool_to_f32(t.mute)),
+            (TrackProperty::Solo, bool_to_f32(t.solo)),
+            (TrackProperty::MidiOutput, bool_to_f32(t.midi_output)),
+        ]
+    };
+    let current_properties = current.map(properties);
+    for (idx, (property, value)) in properties(target).into_iter().enumerate() {
+        if current_properties.map(|p| p[idx].1) != Some(value) {
+            commands.push(Command::UpdateTrack(target.id, property, value));
+        }
+    }
+    if let Some(midi_input) = target.midi_input.as_ref() {
+        if current.map(|t| &t.midi_input) != Some(&target.midi_input) {
+            commands.push(Command::UpdateTrackMidiInput(
+                target.id,
+                midi_input_from_proto(midi_input)?,
+            ));
+        }
+    }
+    if let Some(audio_input) = target.audio_input.as_ref() {
+        if current.map(|t| &t.audio_input) != Some(&target.audio_input) {
+            commands.push(Command::UpdateTrackAudioInput(
+                target.id,
+                audio_input_from_proto(audio_input)?,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Fail with `ResourceExhausted` if `count` objects do not fit within `max`,
+/// the number of objects that the audio thread has room for.
+fn check_capacity(
+    count: usize,
+    max: usize,
+    what: impl FnOnce() -> String,
+) -> Result<(), tonic::Status> {
+    if count > max {
+        return Err(tonic::Status::new(
+            tonic::Code::ResourceExhausted,
+            format!("{} is limited to {}", what(), max),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the audio thread has room for everything in `target` while the
+/// session changes from `current`.
+fn check_session_capacity(current: &Snapshot, target: &Snapshot) -> Result<(), tonic::Status> {
+    // Deleted tracks are removed after new tracks are created.
+    let tracks = current
+        .tracks
+        .keys()
+        .chain(
+            target
+                .tracks
+                .keys()
+                .filter(|id| !current.tracks.contains_key(id)),
+        )
+        .filter(|id| **id != peppermint_core::MASTER_TRACK_ID)
+        .count();
+    check_capacity(tracks, peppermint_core::MAX_TRACKS, || {
+        "the number of tracks".to_string()
+    })?;
+    for track in target.tracks.values() {
+        check_capacity(
+            track.plugin_instances.len(),
+            peppermint_core::track::MAX_PLUGIN_INSTANCES,
+            || format!("the number of plugin instances on track {}", track.id),
+        )?;
+        check_capacity(track.sends.len(), peppermint_core::track::MAX_SENDS, || {
+            format!("the number of sends on track {}", track.id)
+        })?;
+        check_capacity(
+            track.midi_clips.len(),
+            peppermint_core::sequencer::MAX_MIDI_CLIPS,
+            || format!("the number of midi clips on track {}", track.id),
+        )?;
+        check_capacity(
+            track.audio_clips.len(),
+            peppermint_core::track::MAX_AUDIO_CLIPS,
+            || format!("the number of audio clips on track {}", track.id),
+        )?;
+    }
+    Ok(())
+}
+
+/// Send levels are linear gains so they must be finite and not negative.
+fn validate_send_level(level: f32) -> Result<(), tonic::Status> {
+    if !level.is_finite() || level < 0.0 {
+        return Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            format!("{} is not a valid send level", level),
+        ));
+    }
+    Ok(())
+}
+
+fn push_plugin_instance_properties(
+    commands: &mut Vec<Command>,
+    plugin_instance: &peppermint_proto::PluginInstance,
+) {
+    commands.push(Command::UpdatePluginInstanceProperty {
+        id: plugin_instance.id,
+        property: peppermint_core::track::InstanceProperty::Bypass,
+        value: bool_to_f32(plugin_instance.bypassed),
+    });
+    commands.push(Command::UpdatePluginInstanceProperty {
+        id: plugin_instance.id,
+        property: peppermint_core::track::InstanceProperty::Mix,
+        value: plugin_instance.mix,
+    });
+}
+
+fn bool_to_f32(b: bool) -> f32 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn midi_input_to_proto(
+    midi_input: &peppermint_core::midi::MidiInput,
+) -> peppermint_proto::MidiInput {
+    peppermint_proto::MidiInput {
+        port: midi_input.port as u32,
+        channel: midi_input.channel.map(|c| c as u32 + 1).unwrap_or(0),
+        note_range: midi_input
+            .note_range
+            .map(|(low, high)| peppermint_proto::NoteRange {
+                low: low as u32,
+                high: high as u32,
+            }),
+    }
+}
+
+fn midi_input_from_proto(
+    midi_input: &peppermint_proto::MidiInput,
+) -> Result<peppermint_core::midi::MidiInput, tonic::Status> {
+    let channel = match midi_input.channel {
+        0 => None,
+        c @ 1..=16 => Some(c as u8 - 1),
+        c => {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("midi channel {} is not within 0 to 16", c),
+            ))
+        }
+    };
+    let note_range = match midi_input.note_range.as_ref() {
+        None => None,
+        Some(r) if r.low <= r.high && r.high <= 127 => Some((r.low as u8, r.high as u8)),
+        Some(r) => {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("note range {} to {} is not valid", r.low, r.high),
+            ))
+        }
+    };
+    Ok(peppermint_core::midi::MidiInput {
+        port: midi_input.port as usize,
+        channel,
+        note_range,
+    })
+}
+
+fn audio_input_to_proto(
+    audio_input: &peppermint_core::track::AudioInput,
+) -> peppermint_proto::AudioInput {
+    let channels = match audio_input.source {
+        peppermint_core::track::InputSource::None => Vec::new(),
+        peppermint_core::track::InputSource::Mono(channel) => vec![channel as u32],
+        peppermint_core::track::InputSource::Stereo(left, right) => {
+            vec![left as u32, right as u32]
+        }
+    };
+    peppermint_proto::AudioInput {
+        channels,
+        monitoring: audio_input.monitoring,
+    }
+}
+
+fn audio_input_from_proto(
+    audio_input: &peppermint_proto::AudioInput,
+) -> Result<peppermint_core::track::AudioInput, tonic::Status> {
+    let source = match audio_input.channels.as_slice() {
+        [] => peppermint_core::track::InputSource::None,
+        [channel] => peppermint_core::track::InputSource::Mono(*channel as usize),
+        [left, right] => {
+            peppermint_core::track::InputSource::Stereo(*left as usize, *right as usize)
+        }
+        channels => {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "audio input has {} channels but at most 2 are supported",
+                    channels.len()
+                ),
+            ))
+        }
+    };
+    Ok(peppermint_core::track::AudioInput {
+        source,
+        monitoring: audio_input.monitoring,
+    })
+}
+
+/// Convert `clip` to a `MidiClip` for the core.
+fn midi_clip_from_proto(
+    clip: &peppermint_proto::MidiClip,
+) -> Result<peppermint_core::sequencer::MidiClip, tonic::Status> {
+    let is_valid_beat = |beat: f64| beat.is_finite() && beat >= 0.0;
+    if !is_valid_beat(clip.start) || !is_valid_beat(clip.length) {
+        return Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            format!(
+                "midi clip with start {} and length {} is not valid",
+                clip.start, clip.length
+            ),
+        ));
+    }
+    let mut notes = Vec::with_capacity(clip.notes.len());
+    for note in clip.notes.iter() {
+        if !is_valid_beat(note.start) || !is_valid_beat(note.length) {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "note with start {} and length {} is not valid",
+                    note.start, note.length
+                ),
+            ));
+        }
+        if note.pitch > 127 {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("note pitch {} is not within 0 to 127", note.pitch),
+            ));
+        }
+        if !(1..=127).contains(&note.velocity) {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("note velocity {} is not within 1 to 127", note.velocity),
+            ));
+        }
+        if !(1..=16).contains(&note.channel) {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!("midi channel {} is not within 1 to 16", note.channel),
+            ));
+        }
+        notes.push(peppermint_core::sequencer::ClipNote {
+            start: note.start,
+            length: note.length,
+            pitch: note.pitch as u8,
+            velocity: note.velocity as u8,
+            channel: note.channel as u8 - 1,
+        });
+    }
+    Ok(peppermint_core::sequencer::MidiClip {
+        id: clip.id,
+        start: clip.start,
+        length: clip.length,
+        notes,
+    })
+}
+
+fn clamp_to_port(port: &livi::Port, value: f32) -> f32 {
+    let value = port.min_value.map_or(value, |min| value.max(min));
+    port.max_value.map_or(value, |max| value.min(max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_manager() -> PeppermintManager {
+        let (commands, _) = ringbuf::RingBuffer::<SequencedCommand>::new(1024).split();
+        let (_, applied_commands) = tokio::sync::watch::channel(0);
+        PeppermintManager::new(44100.0, 128, commands, applied_commands)
+    }
+
+    fn new_track(id: peppermint_core::Id) -> peppermint_proto::Track {
+        peppermint_proto::Track {
+            id,
+            name: format!("Track {}", id),
+            gain: 1.0,
+            ..peppermint_proto::Track::default()
+        }
+    }
+
+    fn create_track(manager: &mut PeppermintManager) -> peppermint_core::Id {
+        manager
+            .with_history(|m| {
+                m.create_track(tonic::Request::new(
+                    peppermint_proto::CreateTrackRequest::default(),
+                ))
+            })
+            .unwrap()
+            .into_inner()
+            .track
+            .unwrap()
+            .id
+    }
+
+    fn set_gain(manager: &mut PeppermintManager, track_id: peppermint_core::Id, gain: f32) {
+        manager
+            .with_history(|m| {
+                m.update_track(tonic::Request::new(peppermint_proto::UpdateTrackRequest {
+                    track_id,
+                    updates: vec![peppermint_proto::TrackPropertyUpdate {
+                        property: peppermint_proto::track_property_update::TrackProperty::Gain
+                            as i32,
+                        value: gain,
+                    }],
+                    ..peppermint_proto::UpdateTrackRequest::default()
+                }))
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn session_diff_of_the_same_session_is_empty() {
+        let manager = new_manager();
+        let snapshot = manager.snapshot();
+        let session = manager.session_diff(&snapshot, &snapshot).unwrap();
+        assert!(session.commands.is_empty());
+        assert!(session.meters.is_empty());
+    }
+
+    #[test]
+    fn session_diff_creates_new_objects() {
+        let manager = new_manager();
+        let current = manager.snapshot();
+        let mut target = current.clone();
+        let mut track = new_track(2);
+        track.sends.push(peppermint_proto::Send {
+            id: 3,
+            destination_track_id: peppermint_core::MASTER_TRACK_ID,
+            level: 0.5,
+            pre_fader: false,
+        });
+        track.midi_clips.push(peppermint_proto::MidiClip {
+            id: 4,
+            start: 0.0,
+            length: 4.0,
+            notes: Vec::new(),
+        });
+        target.tracks.insert(track.id, track);
+        target.tempo.beats_per_minute = 140.0;
+
+        let session = manager.session_diff(&current, &target).unwrap();
+        let count = |f: fn(&Command) -> bool| session.commands.iter().filter(|c| f(c)).count();
+        assert_eq!(
+            count(|c| matches!(c, Command::CreateTrack(t) if t.id() == 2)),
+            1
+        );
+        assert_eq!(
+            count(|c| matches!(c, Command::CreateSend { track: 2, .. })),
+            1
+        );
+        assert_eq!(
+            count(|c| matches!(c, Command::SetMidiClip { track: 2, .. })),
+            1
+        );
+        assert_eq!(count(|c| matches!(c, Command::SetTempo(_))), 1);
+        assert_eq!(session.meters.len(), 1);
+    }
+
+    #[test]
+    fn session_diff_deletes_removed_objects() {
+        let manager = new_manager();
+        let mut current = manager.snapshot();
+        let mut track = new_track(2);
+        track.sends.push(peppermint_proto::Send {
+            id: 3,
+            destination_track_id: peppermint_core::MASTER_TRACK_ID,
+            level: 0.5,
+            pre_fader: false,
+        });
+        current.tracks.insert(track.id, track);
+        let target = manager.snapshot();
+
+        let session = manager.session_diff(&current, &target).unwrap();
+        let count = |f: fn(&Command) -> bool| session.commands.iter().filter(|c| f(c)).count();
+        assert_eq!(count(|c| matches!(c, Command::DeleteSend { id: 3 })), 1);
+        assert_eq!(count(|c| matches!(c, Command::DeleteTrack(2))), 1);
+        assert_eq!(session.commands.len(), 2);
+    }
+
+    #[test]
+    fn session_diff_updates_changed_tracks() {
+        let manager = new_manager();
+        let mut current = manager.snapshot();
+        current.tracks.insert(2, new_track(2));
+        let mut target = current.clone();
+        target.tracks.get_mut(&2).unwrap().gain = 0.5;
+
+        let session = manager.session_diff(&current, &target).unwrap();
+        assert_eq!(session.commands.len(), 1);
+        assert!(matches!(
+            session.commands[0],
+            Command::UpdateTrack(2, peppermint_core::track::TrackProperty::Gain, gain)
+                if gain == 0.5
+        ));
+    }
+
+    #[test]
+    fn history_only_keeps_changed_tracks() {
+        let mut manager = new_manager();
+        let first = create_track(&mut manager);
+        create_track(&mut manager);
+        set_gain(&mut manager, first, 0.5);
+        let change = manager.history.pop_undo().unwrap();
+        assert_eq!(change.tracks.len(), 1);
+        assert_eq!(change.tracks[0].0, first);
+        assert_eq!(change.tracks[0].1.as_ref().unwrap().gain, 1.0);
+    }
+
+    #[test]
+    fn undo_and_redo_round_trip() {
+        let mut manager = new_manager();
+        let initial = manager.tracks.clone();
+        let track_id = create_track(&mut manager);
+        let created = manager.tracks.clone();
+        set_gain(&mut manager, track_id, 0.5);
+        let updated = manager.tracks.clone();
+
+        manager.undo().unwrap();
+        assert_eq!(manager.tracks, created);
+        manager.undo().unwrap();
+        assert_eq!(manager.tracks, initial);
+        assert!(manager.undo().is_err());
+
+        manager.redo().unwrap();
+        assert_eq!(manager.tracks, created);
+        manager.redo().unwrap();
+        assert_eq!(manager.tracks, updated);
+        assert!(manager.redo().is_err());
+
+        manager.undo().unwrap();
+        assert_eq!(manager.tracks, created);
+    }
+
+    #[test]
+    fn undo_restores_deleted_tracks_and_sends() {
+        let mut manager = new_manager();
+        let source = create_track(&mut manager);
+        let destination = create_track(&mut manager);
+        manager
+            .with_history(|m| {
+                m.create_send(tonic::Request::new(peppermint_proto::CreateSendRequest {
+                    source_track_id: source,
+                    destination_track_id: destination,
+                    level: 0.5,
+                    pre_fader: false,
+                }))
+            })
+            .unwrap();
+        let before = manager.tracks.clone();
+        manager
+            .with_history(|m| {
+                m.delete_track(tonic::Request::new(peppermint_proto::DeleteTrackRequest {
+                    track_id: destination,
+                }))
+            })
+            .unwrap();
+        assert!(manager.tracks[&source].sends.is_empty());
+
+        manager.undo().unwrap();
+        assert_eq!(manager.tracks, before);
+        assert_eq!(manager.send_to_track.len(), 1);
+        manager.redo().unwrap();
+        assert!(!manager.tracks.contains_key(&destination));
+        assert!(manager.tracks[&source].sends.is_empty());
+    }
 }
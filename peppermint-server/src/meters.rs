@@ -0,0 +1,80 @@
+use peppermint_core::meter::Meter;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::mpsc::Sender;
+
+/// The rate that meters are sent at if the client does not choose one.
+const DEFAULT_RATE_HZ: f32 = 30.0;
+
+/// The highest rate that meters may be sent at.
+const MAX_RATE_HZ: f32 = 1000.0;
+
+/// The meters for all tracks keyed by track id.
+pub type Meters = Arc<Mutex<HashMap<peppermint_core::Id, Arc<Meter>>>>;
+
+/// The time between updates for a rate of `rate_hz`. A rate of 0 uses the
+/// default rate.
+pub fn interval_from_rate(rate_hz: f32) -> Result<Duration, tonic::Status> {
+    let rate_hz = if rate_hz == 0.0 {
+        DEFAULT_RATE_HZ
+    } else {
+        rate_hz
+    };
+    if !rate_hz.is_finite() || rate_hz <= 0.0 || rate_hz > MAX_RATE_HZ {
+        return Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            format!("rate {} is not within 0 to {}", rate_hz, MAX_RATE_HZ),
+        ));
+    }
+    Ok(Duration::from_secs_f32(1.0 / rate_hz))
+}
+
+/// Send the readings of all `meters` every `interval` until `updates` is
+/// closed.
+pub async fn watch(
+    meters: Meters,
+    interval: Duration,
+    updates: Sender<Result<peppermint_proto::WatchMetersResponse, tonic::Status>>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut last_clips: HashMap<peppermint_core::Id, u64> = HashMap::new();
+    loop {
+        ticker.tick().await;
+        let readings = match read_all(&meters) {
+            Ok(readings) => readings,
+            Err(e) => {
+                let _ = updates.send(Err(e)).await;
+                return;
+            }
+        };
+        let mut response = peppermint_proto::WatchMetersResponse {
+            meters: Vec::with_capacity(readings.len()),
+        };
+        for (track_id, reading) in readings {
+            let last_clips = last_clips.entry(track_id).or_insert(reading.clips);
+            response.meters.push(peppermint_proto::TrackMeter {
+                track_id,
+                peak: reading.peak.to_vec(),
+                rms: reading.rms.to_vec(),
+                clipped: reading.clips != *last_clips,
+            });
+            *last_clips = reading.clips;
+        }
+        if updates.send(Ok(response)).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn read_all(
+    meters: &Meters,
+) -> Result<Vec<(peppermint_core::Id, peppermint_core::meter::MeterReading)>, tonic::Status> {
+    let meters = meters
+        .lock()
+        .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+    Ok(meters.iter().map(|(id, m)| (*id, m.read())).collect())
+}
@@ -0,0 +1,339 @@
+use log::warn;
+use peppermint_proto::{PluginState, PluginStateProperty};
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+
+/// The URI of the LV2 State interface.
+const STATE_INTERFACE_URI: &str = "http://lv2plug.in/ns/ext/state#interface";
+
+/// Saves the state of a plugin instance that may be owned by the audio thread.
+/// The LV2 State extension allows `save` to run at the same time as the plugin
+/// processes audio.
+pub struct StateHandle {
+    handle: lv2_sys::LV2_Handle,
+    interface: NonNull<lv2_sys::LV2_State_Interface>,
+}
+
+// The handle is only used through `save`, which plugins must make thread safe.
+unsafe impl Send for StateHandle {}
+
+impl StateHandle {
+    /// Returns `None` if the plugin does not support the LV2 State extension.
+    pub fn new(instance: &livi::Instance) -> Option<StateHandle> {
+        let interface = unsafe { instance.extension_data(STATE_INTERFACE_URI)? };
+        Some(StateHandle {
+            handle: instance.raw_handle(),
+            interface,
+        })
+    }
+
+    /// Save the state of the plugin instance as an encoded `PluginState`.
+    /// Paths to files within `dir` are stored relative to it.
+    ///
+    /// # Safety
+    /// The plugin instance that the handle was created from must not have been
+    /// dropped.
+    pub unsafe fn save(
+        &self,
+        features: &livi::Features,
+        dir: Option<&Path>,
+    ) -> Result<Vec<u8>, String> {
+        let save = self
+            .interface
+            .as_ref()
+            .save
+            .ok_or_else(|| "plugin does not implement state save".to_string())?;
+        let mut context = SaveContext {
+            features,
+            state: PluginState::default(),
+            skipped_keys: Vec::new(),
+        };
+        let path_features = PathFeatures::new(dir);
+        let features = path_features.features();
+        let flags = lv2_sys::LV2_State_Flags::LV2_STATE_IS_POD.0
+            | lv2_sys::LV2_State_Flags::LV2_STATE_IS_PORTABLE.0;
+        let status = save(
+            self.handle,
+            Some(store),
+            &mut context as *mut SaveContext as *mut c_void,
+            flags,
+            features.as_ptr(),
+        );
+        if status != lv2_sys::LV2_State_Status_LV2_STATE_SUCCESS {
+            return Err(format!(
+                "plugin failed to save state with status {}",
+                status
+            ));
+        }
+        for key in context.skipped_keys {
+            warn!(
+                "State property {} was not saved since it is not plain old data.",
+                key
+            );
+        }
+        Ok(prost::Message::encode_to_vec(&context.state))
+    }
+}
+
+/// Restore `state`, an encoded `PluginState`, on `instance`. Does nothing if
+/// `state` is empty. Relative paths in `state` are resolved against `dir`. This
+/// must be called before `instance` is sent to the audio thread since the
+/// plugin may not process audio while its state is restored.
+pub fn restore(
+    instance: &mut livi::Instance,
+    features: &livi::Features,
+    dir: Option<&Path>,
+    state: &[u8],
+) -> Result<(), String> {
+    if state.is_empty() {
+        return Ok(());
+    }
+    let state: PluginState = prost::Message::decode(state).map_err(|e| e.to_string())?;
+    let interface: NonNull<lv2_sys::LV2_State_Interface> =
+        unsafe { instance.extension_data(STATE_INTERFACE_URI) }
+            .ok_or_else(|| "plugin does not support the LV2 State extension".to_string())?;
+    let restore = unsafe { interface.as_ref() }
+        .restore
+        .ok_or_else(|| "plugin does not implement state restore".to_string())?;
+    let context = RestoreContext {
+        properties: state
+            .properties
+            .into_iter()
+            .map(|p| {
+                let key = CString::new(p.key).map_err(|e| e.to_string())?;
+                let type_uri = CString::new(p.type_uri).map_err(|e| e.to_string())?;
+                Ok(RestoreProperty {
+                    key: features.urid(&key),
+                    type_urid: features.urid(&type_uri),
+                    flags: p.flags,
+                    value: p.value,
+                })
+            })
+            .collect::<Result<_, String>>()?,
+    };
+    let path_features = PathFeatures::new(dir);
+    let features = path_features.features();
+    let flags = lv2_sys::LV2_State_Flags::LV2_STATE_IS_POD.0
+        | lv2_sys::LV2_State_Flags::LV2_STATE_IS_PORTABLE.0;
+    let status = unsafe {
+        restore(
+            instance.raw_handle(),
+            Some(retrieve),
+            &context as *const RestoreContext as *mut c_void,
+            flags,
+            features.as_ptr(),
+        )
+    };
+    if status != lv2_sys::LV2_State_Status_LV2_STATE_SUCCESS {
+        return Err(format!(
+            "plugin failed to restore state with status {}",
+            status
+        ));
+    }
+    Ok(())
+}
+
+struct SaveContext<'a> {
+    features: &'a livi::Features,
+    state: PluginState,
+    /// The keys of the properties that were not stored.
+    skipped_keys: Vec<String>,
+}
+
+struct RestoreProperty {
+    key: u32,
+    type_urid: u32,
+    flags: u32,
+    value: Vec<u8>,
+}
+
+struct RestoreContext {
+    properties: Vec<RestoreProperty>,
+}
+
+unsafe extern "C" fn store(
+    handle: lv2_sys::LV2_State_Handle,
+    key: u32,
+    value: *const c_void,
+    size: usize,
+    type_urid: u32,
+    flags: u32,
+) -> lv2_sys::LV2_State_Status {
+    let context = &mut *(handle as *mut SaveContext);
+    let (key, type_uri) = match (context.features.uri(key), context.features.uri(type_urid)) {
+        (Some(key), Some(type_uri)) => (key.to_string(), type_uri.to_string()),
+        _ => return lv2_sys::LV2_State_Status_LV2_STATE_ERR_UNKNOWN,
+    };
+    // Values that are not plain old data can not be written to a project.
+    if flags & lv2_sys::LV2_State_Flags::LV2_STATE_IS_POD.0 == 0 {
+        context.skipped_keys.push(key);
+        return lv2_sys::LV2_State_Status_LV2_STATE_ERR_BAD_FLAGS;
+    }
+    let value = if value.is_null() || size == 0 {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(value as *const u8, size).to_vec()
+    };
+    context.state.properties.push(PluginStateProperty {
+        key,
+        type_uri,
+        flags,
+        value,
+    });
+    lv2_sys::LV2_State_Status_LV2_STATE_SUCCESS
+}
+
+unsafe extern "C" fn retrieve(
+    handle: lv2_sys::LV2_State_Handle,
+    key: u32,
+    size: *mut usize,
+    type_urid: *mut u32,
+    flags: *mut u32,
+) -> *const c_void {
+    let context = &*(handle as *const RestoreContext);
+    let property = match context.properties.iter().find(|p| p.key == key) {
+        Some(p) => p,
+        None => return std::ptr::null(),
+    };
+    if !size.is_null() {
+        *size = property.value.len();
+    }
+    if !type_urid.is_null() {
+        *type_urid = property.type_urid;
+    }
+    if !flags.is_null() {
+        *flags = property.flags;
+    }
+    property.value.as_ptr() as *const c_void
+}
+
+/// The `state:mapPath` and `state:freePath` features. Paths within `dir` are
+/// mapped to paths relative to it so that projects can be moved along with
+/// their files. Other paths are kept as they are.
+struct PathFeatures {
+    dir: Option<PathBuf>,
+    map_path: lv2_sys::LV2_State_Map_Path,
+    free_path: lv2_sys::LV2_State_Free_Path,
+    features: [lv2_sys::LV2_Feature; 2],
+}
+
+impl PathFeatures {
+    fn new(dir: Option<&Path>) -> Box<PathFeatures> {
+        let mut path_features = Box::new(PathFeatures {
+            dir: dir.map(Path::to_path_buf),
+            map_path: lv2_sys::LV2_State_Map_Path {
+                handle: std::ptr::null_mut(),
+                abstract_path: Some(abstract_path),
+                absolute_path: Some(absolute_path),
+            },
+            free_path: lv2_sys::LV2_State_Free_Path {
+                handle: std::ptr::null_mut(),
+                free_path: Some(free_path),
+            },
+            features: [
+                lv2_sys::LV2_Feature {
+                    URI: lv2_sys::LV2_STATE__mapPath.as_ptr() as *const c_char,
+                    data: std::ptr::null_mut(),
+                },
+                lv2_sys::LV2_Feature {
+                    URI: lv2_sys::LV2_STATE__freePath.as_ptr() as *const c_char,
+                    data: std::ptr::null_mut(),
+                },
+            ],
+        });
+        // The box keeps the addresses stable.
+        let dir_ptr: *mut Option<PathBuf> = &mut path_features.dir;
+        path_features.map_path.handle = dir_ptr as *mut c_void;
+        let map_path_ptr: *mut lv2_sys::LV2_State_Map_Path = &mut path_features.map_path;
+        path_features.features[0].data = map_path_ptr as *mut c_void;
+        let free_path_ptr: *mut lv2_sys::LV2_State_Free_Path = &mut path_features.free_path;
+        path_features.features[1].data = free_path_ptr as *mut c_void;
+        path_features
+    }
+
+    /// The null terminated feature list to pass to `save` or `restore`.
+    fn features(&self) -> [*const lv2_sys::LV2_Feature; 3] {
+        [&self.features[0], &self.features[1], std::ptr::null()]
+    }
+}
+
+/// Map `path` to the path that is stored in the state.
+fn to_abstract_path(dir: Option<&Path>, path: &Path) -> PathBuf {
+    match dir.and_then(|dir| path.strip_prefix(dir).ok()) {
+        Some(relative) => relative.to_path_buf(),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Map `path` from the state to the path of the file.
+fn to_absolute_path(dir: Option<&Path>, path: &Path) -> PathBuf {
+    match dir {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Copy `path` into a string that the plugin frees with `free_path`, or
+/// `free` if it does not support `state:freePath`.
+fn to_c_path(path: &Path) -> *mut c_char {
+    let path = match CString::new(path.to_string_lossy().into_owned()) {
+        Ok(path) => path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    unsafe { libc::strdup(path.as_ptr()) }
+}
+
+unsafe extern "C" fn abstract_path(
+    handle: lv2_sys::LV2_State_Map_Path_Handle,
+    absolute_path: *const c_char,
+) -> *mut c_char {
+    let dir = &*(handle as *const Option<PathBuf>);
+    let path = CStr::from_ptr(absolute_path).to_string_lossy();
+    to_c_path(&to_abstract_path(dir.as_deref(), Path::new(path.as_ref())))
+}
+
+unsafe extern "C" fn absolute_path(
+    handle: lv2_sys::LV2_State_Map_Path_Handle,
+    abstract_path: *const c_char,
+) -> *mut c_char {
+    let dir = &*(handle as *const Option<PathBuf>);
+    let path = CStr::from_ptr(abstract_path).to_string_lossy();
+    to_c_path(&to_absolute_path(dir.as_deref(), Path::new(path.as_ref())))
+}
+
+unsafe extern "C" fn free_path(_: lv2_sys::LV2_State_Free_Path_Handle, path: *mut c_char) {
+    libc::free(path as *mut c_void);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_within_dir_are_relative() {
+        let dir = Path::new("/projects/song");
+        assert_eq!(
+            to_abstract_path(Some(dir), Path::new("/projects/song/samples/kick.wav")),
+            Path::new("samples/kick.wav")
+        );
+        assert_eq!(
+            to_absolute_path(Some(dir), Path::new("samples/kick.wav")),
+            Path::new("/projects/song/samples/kick.wav")
+        );
+    }
+
+    #[test]
+    fn paths_outside_dir_are_kept() {
+        let dir = Path::new("/projects/song");
+        let path = Path::new("/samples/kick.wav");
+        assert_eq!(to_abstract_path(Some(dir), path), path);
+        assert_eq!(to_absolute_path(Some(dir), path), path);
+        assert_eq!(to_abstract_path(None, path), path);
+        assert_eq!(
+            to_absolute_path(None, Path::new("kick.wav")),
+            Path::new("kick.wav")
+        );
+    }
+}
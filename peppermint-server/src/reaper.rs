@@ -0,0 +1,48 @@
+use log::{debug, warn};
+use peppermint_core::command::Garbage;
+use peppermint_core::overflow::OverflowCounter;
+use ringbuf::Consumer;
+use std::sync::Arc;
+
+/// Deallocates objects that were removed by the audio thread and reports the
+/// overflows counted by the audio thread. This runs forever and should be run
+/// on a thread that is not realtime.
+pub fn run(
+    garbage: Consumer<Garbage>,
+    overflows: Arc<OverflowCounter>,
+    interval: std::time::Duration,
+) {
+    let mut garbage = garbage;
+    loop {
+        std::thread::sleep(interval);
+        let count = garbage.pop_each(
+            |g| {
+                drop(g);
+                true
+            },
+            None,
+        );
+        if count > 0 {
+            debug!("Deallocated {} objects from the audio thread.", count);
+        }
+        let overflows = overflows.take();
+        if overflows.garbage > 0 {
+            warn!(
+                "Garbage queue was full, deallocated {} objects on the audio thread.",
+                overflows.garbage
+            );
+        }
+        if overflows.recordings > 0 {
+            warn!(
+                "Too many recordings, {} recordings were not started.",
+                overflows.recordings
+            );
+        }
+        if overflows.midi_bindings > 0 {
+            warn!(
+                "Too many midi bindings, {} bindings were not created.",
+                overflows.midi_bindings
+            );
+        }
+    }
+}
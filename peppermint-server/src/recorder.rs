@@ -0,0 +1,103 @@
+use peppermint_core::recording::{Recording, RecordingSource};
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+/// How often the writer thread moves frames from the audio thread to the file.
+const WRITE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The number of seconds of audio that may be buffered before frames are
+/// dropped.
+const BUFFER_SECONDS: f64 = 2.0;
+
+/// How long to wait for the audio thread to stop sending frames to a recording
+/// before its file is finished anyway.
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Writes the frames of a `Recording` to a WAV file on its own thread.
+pub struct Recorder {
+    stop: Arc<AtomicBool>,
+    dropped_frames: Arc<AtomicU64>,
+    thread: JoinHandle<Result<u64, hound::Error>>,
+}
+
+impl Recorder {
+    /// Start writing to the WAV file at `path`. The returned `Recording`
+    /// should be sent to the audio thread.
+    pub fn start(
+        id: peppermint_core::Id,
+        source: RecordingSource,
+        path: &Path,
+        sample_rate: f64,
+    ) -> Result<(Recorder, Recording), hound::Error> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        let (frames_tx, mut frames_rx) =
+            ringbuf::RingBuffer::<[f32; 2]>::new((sample_rate * BUFFER_SECONDS) as usize).split();
+        let stop = Arc::new(AtomicBool::new(false));
+        let dropped_frames = Arc::new(AtomicU64::new(0));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut buffer = vec![[0.0; 2]; 4096];
+                let mut frames = 0;
+                loop {
+                    // Wait one more interval after a stop so that frames from
+                    // the last few cycles are written.
+                    let stopping = stop.load(Ordering::Acquire);
+                    std::thread::sleep(WRITE_INTERVAL);
+                    loop {
+                        let count = frames_rx.pop_slice(&mut buffer);
+                        if count == 0 {
+                            break;
+                        }
+                        for frame in buffer[..count].iter() {
+                            writer.write_sample(frame[0])?;
+                            writer.write_sample(frame[1])?;
+                        }
+                        frames += count as u64;
+                    }
+                    if stopping {
+                        break;
+                    }
+                }
+                writer.finalize()?;
+                Ok(frames)
+            })
+        };
+        let recording = Recording::new(id, source, frames_tx, dropped_frames.clone());
+        Ok((
+            Recorder {
+                stop,
+                dropped_frames,
+                thread,
+            },
+            recording,
+        ))
+    }
+
+    /// Finish writing the file. Returns the number of frames that were written
+    /// and the number of frames that were dropped. This blocks until the
+    /// writer thread is done and should be called once the audio thread has
+    /// stopped sending frames.
+    pub fn stop(self) -> Result<(u64, u64), String> {
+        self.stop.store(true, Ordering::Release);
+        let frames = self
+            .thread
+            .join()
+            .map_err(|_| "recording thread panicked".to_string())?
+            .map_err(|e| e.to_string())?;
+        Ok((frames, self.dropped_frames.load(Ordering::Relaxed)))
+    }
+}
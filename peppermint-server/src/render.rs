@@ -0,0 +1,114 @@
+use crate::{command_queue::CommandQueue, streamer};
+use log::{error, info};
+use peppermint_core::command::{Command, Garbage};
+use ringbuf::Consumer;
+use tokio::sync::mpsc::Sender;
+
+/// The number of blocks between progress updates.
+const PROGRESS_INTERVAL_BLOCKS: u64 = 64;
+
+/// A copy of the session that renders to a WAV file.
+pub struct RenderJob {
+    pub core: peppermint_core::PeppermintCore,
+    pub commands: CommandQueue,
+    pub garbage: Consumer<Garbage>,
+    pub streams: Vec<streamer::Stream>,
+    pub path: std::path::PathBuf,
+    pub start_frame: u64,
+    pub end_frame: u64,
+    pub sample_rate: f64,
+    pub buffer_size: usize,
+}
+
+/// Render `job` as fast as possible and report progress to `progress`. The
+/// render stops early if `progress` is closed.
+pub fn run(
+    job: RenderJob,
+    progress: Sender<Result<peppermint_proto::RenderProgress, tonic::Status>>,
+) {
+    let path = job.path.clone();
+    match render(job, &progress) {
+        Ok(true) => info!("Finished rendering {}.", path.to_string_lossy()),
+        Ok(false) => info!("Render of {} was cancelled.", path.to_string_lossy()),
+        Err(e) => {
+            error!("Failed to render {}: {}", path.to_string_lossy(), e);
+            let _ = progress.blocking_send(Err(tonic::Status::new(tonic::Code::Internal, e)));
+        }
+    }
+}
+
+/// Returns true if the render was completed and false if it was cancelled.
+fn render(
+    mut job: RenderJob,
+    progress: &Sender<Result<peppermint_proto::RenderProgress, tonic::Status>>,
+) -> Result<bool, String> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: job.sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(&job.path, spec).map_err(|e| e.to_string())?;
+    let mut out = peppermint_core::channels::FixedChannels::<2>::new(job.buffer_size);
+    job.core.set_sample_rate(job.sample_rate);
+    job.core.set_smoothing_samples(0);
+    // Process one block while stopped so that audio clips request audio from
+    // the start frame instead of preloading ahead of it.
+    job.core.process(
+        peppermint_core::IO {
+            audio_in: &[],
+            audio_out: &mut out,
+            midi: std::iter::empty(),
+            midi_out: None,
+        },
+        job.buffer_size,
+    );
+    job.commands
+        .push(Command::Play)
+        .map_err(|_| "failed to send command".to_string())?;
+    let total_frames = job.end_frame - job.start_frame;
+    let mut rendered_frames = 0;
+    let mut blocks = 0;
+    while rendered_frames < total_frames {
+        let samples = (job.buffer_size as u64).min(total_frames - rendered_frames) as usize;
+        for stream in job.streams.iter_mut() {
+            stream.fill().map_err(|e| e.to_string())?;
+        }
+        job.core.process(
+            peppermint_core::IO {
+                audio_in: &[],
+                audio_out: &mut out,
+                midi: std::iter::empty(),
+                midi_out: None,
+            },
+            samples,
+        );
+        let mut channels = out.iter_channels();
+        if let (Some(left), Some(right)) = (channels.next(), channels.next()) {
+            for (l, r) in left[..samples].iter().zip(right[..samples].iter()) {
+                writer.write_sample(*l).map_err(|e| e.to_string())?;
+                writer.write_sample(*r).map_err(|e| e.to_string())?;
+            }
+        }
+        job.garbage.pop_each(|_| true, None);
+        rendered_frames += samples as u64;
+        blocks += 1;
+        if blocks % PROGRESS_INTERVAL_BLOCKS == 0 {
+            let update = peppermint_proto::RenderProgress {
+                rendered_frames,
+                total_frames,
+                done: false,
+            };
+            if progress.blocking_send(Ok(update)).is_err() {
+                return Ok(false);
+            }
+        }
+    }
+    writer.finalize().map_err(|e| e.to_string())?;
+    let _ = progress.blocking_send(Ok(peppermint_proto::RenderProgress {
+        rendered_frames,
+        total_frames,
+        done: true,
+    }));
+    Ok(true)
+}
@@ -0,0 +1,154 @@
+use log::debug;
+use peppermint_proto::{session_event::Kind, PluginInstance, SessionEvent, Track};
+use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc};
+
+/// The events that change the tracks in `before` to the tracks in `after`.
+/// The revisions of the events are not set.
+pub fn diff(
+    before: &HashMap<peppermint_core::Id, Track>,
+    after: &HashMap<peppermint_core::Id, Track>,
+) -> Vec<SessionEvent> {
+    let mut events = Vec::new();
+    let mut before_tracks: Vec<_> = before.values().collect();
+    before_tracks.sort_by_key(|t| t.id);
+    let mut after_tracks: Vec<_> = after.values().collect();
+    after_tracks.sort_by_key(|t| t.id);
+    for track in before_tracks {
+        if !after.contains_key(&track.id) {
+            events.push(event(Kind::TrackDeleted, track.id));
+        }
+    }
+    for track in after_tracks {
+        let before_track = match before.get(&track.id) {
+            Some(t) => t,
+            None => {
+                events.push(SessionEvent {
+                    track: Some(track.clone()),
+                    ..event(Kind::TrackCreated, track.id)
+                });
+                continue;
+            }
+        };
+        if without_plugin_instances(before_track) != without_plugin_instances(track)
+            || !same_order(before_track, track)
+        {
+            events.push(SessionEvent {
+                track: Some(track.clone()),
+                ..event(Kind::TrackUpdated, track.id)
+            });
+        }
+        for plugin_instance in before_track.plugin_instances.iter() {
+            if find_instance(track, plugin_instance.id).is_none() {
+                events.push(SessionEvent {
+                    plugin_instance_id: plugin_instance.id,
+                    ..event(Kind::PluginInstanceRemoved, track.id)
+                });
+            }
+        }
+        for (index, plugin_instance) in track.plugin_instances.iter().enumerate() {
+            let before_instance = match find_instance(before_track, plugin_instance.id) {
+                Some(p) => p,
+                None => {
+                    events.push(SessionEvent {
+                        plugin_instance_id: plugin_instance.id,
+                        plugin_instance: Some(plugin_instance.clone()),
+                        index: index as u32,
+                        ..event(Kind::PluginInstanceAdded, track.id)
+                    });
+                    continue;
+                }
+            };
+            if before_instance.bypassed != plugin_instance.bypassed
+                || before_instance.mix != plugin_instance.mix
+            {
+                events.push(SessionEvent {
+                    plugin_instance_id: plugin_instance.id,
+                    plugin_instance: Some(plugin_instance.clone()),
+                    index: index as u32,
+                    ..event(Kind::PluginInstanceUpdated, track.id)
+                });
+            }
+            for (param_index, (before_value, value)) in before_instance
+                .params
+                .iter()
+                .zip(plugin_instance.params.iter())
+                .enumerate()
+            {
+                if before_value != value {
+                    events.push(SessionEvent {
+                        plugin_instance_id: plugin_instance.id,
+                        param_index: param_index as u32,
+                        value: *value,
+                        ..event(Kind::ParamChanged, track.id)
+                    });
+                }
+            }
+        }
+    }
+    events
+}
+
+/// Send all events to `updates` until `updates` is closed. The stream ends
+/// with an error if the client falls behind since it can no longer follow the
+/// session.
+pub async fn watch(
+    mut events: broadcast::Receiver<SessionEvent>,
+    updates: mpsc::Sender<Result<SessionEvent, tonic::Status>>,
+) {
+    loop {
+        let update = match events.recv().await {
+            Ok(event) => Ok(event),
+            Err(broadcast::error::RecvError::Lagged(count)) => Err(tonic::Status::new(
+                tonic::Code::Aborted,
+                format!(
+                    "client fell behind and missed {} session events, call GetTracks and watch again",
+                    count
+                ),
+            )),
+            Err(broadcast::error::RecvError::Closed) => {
+                debug!("Session events are no longer produced.");
+                return;
+            }
+        };
+        let is_err = update.is_err();
+        if updates.send(update).await.is_err() || is_err {
+            return;
+        }
+    }
+}
+
+fn event(kind: Kind, track_id: peppermint_core::Id) -> SessionEvent {
+    SessionEvent {
+        kind: kind as i32,
+        track_id,
+        ..SessionEvent::default()
+    }
+}
+
+fn without_plugin_instances(track: &Track) -> Track {
+    Track {
+        plugin_instances: Vec::new(),
+        ..track.clone()
+    }
+}
+
+/// Returns true if the plugin instances that are on both tracks are in the
+/// same order.
+fn same_order(before: &Track, after: &Track) -> bool {
+    let before_order = before
+        .plugin_instances
+        .iter()
+        .filter(|p| find_instance(after, p.id).is_some())
+        .map(|p| p.id);
+    let after_order = after
+        .plugin_instances
+        .iter()
+        .filter(|p| find_instance(before, p.id).is_some())
+        .map(|p| p.id);
+    before_order.eq(after_order)
+}
+
+fn find_instance(track: &Track, id: peppermint_core::Id) -> Option<&PluginInstance> {
+    track.plugin_instances.iter().find(|p| p.id == id)
+}
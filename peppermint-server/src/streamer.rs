@@ -0,0 +1,299 @@
+use log::error;
+use peppermint_core::audio_clip::{
+    AudioClipRegion, StreamBlock, StreamRequest, STREAM_BLOCK_FRAMES,
+};
+use ringbuf::Producer;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The number of blocks that are buffered ahead for each clip.
+const STREAM_BLOCKS: usize = 64;
+
+/// The streams for all audio clips keyed by clip id.
+pub type Streams = Arc<Mutex<HashMap<peppermint_core::Id, Stream>>>;
+
+/// Reads audio for a single clip from disk.
+pub struct Stream {
+    decoder: Decoder,
+    region: AudioClipRegion,
+    blocks: Producer<StreamBlock>,
+    request: Arc<StreamRequest>,
+    /// The generation of the request that is currently being served.
+    generation: Option<u64>,
+    /// The position on the timeline of the next block.
+    cursor: u64,
+    /// Set if reading failed. Failed streams are not read from again.
+    failed: bool,
+}
+
+impl Stream {
+    /// Create a new stream for `decoder` and the clip that plays it back.
+    pub fn new(
+        id: peppermint_core::Id,
+        decoder: Decoder,
+        region: AudioClipRegion,
+    ) -> (Stream, peppermint_core::audio_clip::AudioClip) {
+        let (blocks_tx, blocks_rx) = ringbuf::RingBuffer::new(STREAM_BLOCKS).split();
+        let request = Arc::new(StreamRequest::default());
+        let clip =
+            peppermint_core::audio_clip::AudioClip::new(id, region, blocks_rx, request.clone());
+        let stream = Stream {
+            decoder,
+            region,
+            blocks: blocks_tx,
+            request,
+            generation: None,
+            cursor: region.start,
+            failed: false,
+        };
+        (stream, clip)
+    }
+
+    /// Set the region that is read. The clip requests audio again when it
+    /// receives its new region, so this should be called while holding the
+    /// streams lock that was taken before the region was sent to the clip.
+    pub fn set_region(&mut self, region: AudioClipRegion) {
+        self.region = region;
+        // Seek to the new region on the next fill.
+        self.generation = None;
+    }
+
+    /// Fill the buffer with blocks for the latest request.
+    pub fn fill(&mut self) -> std::io::Result<()> {
+        let (generation, frame) = self.request.get();
+        if self.generation != Some(generation) {
+            self.generation = Some(generation);
+            self.cursor = frame.clamp(self.region.start, self.region.end());
+            self.decoder
+                .seek(self.region.offset + self.cursor - self.region.start)?;
+        }
+        while !self.blocks.is_full() && self.cursor < self.region.end() {
+            let mut block = StreamBlock {
+                generation,
+                frame: self.cursor,
+                len: 0,
+                samples: [[0.0; 2]; STREAM_BLOCK_FRAMES],
+            };
+            let len = STREAM_BLOCK_FRAMES.min((self.region.end() - self.cursor) as usize);
+            block.len = self.decoder.read(&mut block.samples[..len])?;
+            if block.len == 0 {
+                break;
+            }
+            self.cursor += block.len as u64;
+            // The buffer is not full so the push always succeeds.
+            let _ = self.blocks.push(block);
+        }
+        Ok(())
+    }
+}
+
+/// Decodes WAV or FLAC files into stereo frames.
+pub enum Decoder {
+    Wav {
+        reader: hound::WavReader<std::io::BufReader<std::fs::File>>,
+    },
+    Flac(Box<FlacDecoder>),
+}
+
+impl Decoder {
+    /// Open the file at `path`. The format is chosen by the file extension.
+    pub fn open(path: &Path) -> Result<Decoder, String> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("wav") => {
+                let reader = hound::WavReader::open(path).map_err(|e| e.to_string())?;
+                Ok(Decoder::Wav { reader })
+            }
+            Some("flac") => FlacDecoder::open(path.to_path_buf())
+                .map(|decoder| Decoder::Flac(Box::new(decoder)))
+                .map_err(|e| e.to_string()),
+            _ => Err(format!(
+                "{} is not a WAV or FLAC file",
+                path.to_string_lossy()
+            )),
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        match self {
+            Decoder::Wav { reader } => reader.spec().sample_rate as f64,
+            Decoder::Flac(decoder) => decoder.info.sample_rate as f64,
+        }
+    }
+
+    /// The number of frames in the file.
+    pub fn frames(&self) -> u64 {
+        match self {
+            Decoder::Wav { reader } => reader.duration() as u64,
+            Decoder::Flac(decoder) => decoder.info.samples.unwrap_or(0),
+        }
+    }
+
+    fn seek(&mut self, frame: u64) -> std::io::Result<()> {
+        match self {
+            Decoder::Wav { reader } => reader.seek(frame.min(u32::MAX as u64) as u32),
+            Decoder::Flac(decoder) => decoder.seek(frame).map_err(to_io_error),
+        }
+    }
+
+    /// Read frames into `out` and return the number of frames that were read.
+    fn read(&mut self, out: &mut [[f32; 2]]) -> std::io::Result<usize> {
+        match self {
+            Decoder::Wav { reader } => {
+                let spec = reader.spec();
+                let channels = spec.channels as usize;
+                let samples_to_read = out.len() * channels;
+                let mut read = 0;
+                let mut frame = [0.0; 2];
+                let mut push_sample = |idx: usize, sample: f32| {
+                    let channel = idx % channels;
+                    if channel < 2 {
+                        frame[channel] = sample;
+                    }
+                    if channel == channels - 1 {
+                        if channels == 1 {
+                            frame[1] = frame[0];
+                        }
+                        out[read] = frame;
+                        read += 1;
+                    }
+                };
+                match spec.sample_format {
+                    hound::SampleFormat::Float => {
+                        for (idx, sample) in
+                            reader.samples::<f32>().take(samples_to_read).enumerate()
+                        {
+                            push_sample(idx, sample.map_err(to_io_error)?);
+                        }
+                    }
+                    hound::SampleFormat::Int => {
+                        let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                        for (idx, sample) in
+                            reader.samples::<i32>().take(samples_to_read).enumerate()
+                        {
+                            push_sample(idx, sample.map_err(to_io_error)? as f32 * scale);
+                        }
+                    }
+                }
+                Ok(read)
+            }
+            Decoder::Flac(decoder) => decoder.read(out).map_err(to_io_error),
+        }
+    }
+}
+
+/// Decodes a FLAC file one block at a time. claxon does not support seeking so
+/// seeking backwards reopens the file and decodes up to the target frame.
+pub struct FlacDecoder {
+    path: PathBuf,
+    reader: claxon::FlacReader<std::fs::File>,
+    info: claxon::metadata::StreamInfo,
+    /// The frames of the last decoded block.
+    block: Vec<[f32; 2]>,
+    /// The position in the file of the first frame of `block`.
+    block_frame: u64,
+    /// The index of the next frame to read from `block`.
+    position: usize,
+    /// Reused by claxon to decode blocks.
+    buffer: Vec<i32>,
+}
+
+impl FlacDecoder {
+    fn open(path: PathBuf) -> Result<FlacDecoder, claxon::Error> {
+        let reader = claxon::FlacReader::open(&path)?;
+        let info = reader.streaminfo();
+        Ok(FlacDecoder {
+            path,
+            reader,
+            info,
+            block: Vec::with_capacity(info.max_block_size as usize),
+            block_frame: 0,
+            position: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Decode the next block. Returns `false` if the end of the file was
+    /// reached.
+    fn decode_block(&mut self) -> Result<bool, claxon::Error> {
+        let buffer = std::mem::take(&mut self.buffer);
+        let block = match self.reader.blocks().read_next_or_eof(buffer)? {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        let scale = 1.0 / (1u64 << (self.info.bits_per_sample - 1)) as f32;
+        let right = if block.channels() > 1 { 1 } else { 0 };
+        self.block_frame = block.time();
+        self.block.clear();
+        self.block.extend((0..block.duration()).map(|idx| {
+            [
+                block.sample(0, idx) as f32 * scale,
+                block.sample(right, idx) as f32 * scale,
+            ]
+        }));
+        self.position = 0;
+        self.buffer = block.into_buffer();
+        Ok(true)
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), claxon::Error> {
+        if frame < self.block_frame {
+            *self = FlacDecoder::open(std::mem::take(&mut self.path))?;
+        }
+        while frame >= self.block_frame + self.block.len() as u64 {
+            if !self.decode_block()? {
+                self.position = self.block.len();
+                return Ok(());
+            }
+        }
+        self.position = (frame - self.block_frame) as usize;
+        Ok(())
+    }
+
+    fn read(&mut self, out: &mut [[f32; 2]]) -> Result<usize, claxon::Error> {
+        let mut read = 0;
+        while read < out.len() {
+            if self.position == self.block.len() && !self.decode_block()? {
+                break;
+            }
+            let source = &self.block[self.position..];
+            let count = source.len().min(out.len() - read);
+            out[read..read + count].copy_from_slice(&source[..count]);
+            self.position += count;
+            read += count;
+        }
+        Ok(read)
+    }
+}
+
+fn to_io_error(err: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::other(err.to_string())
+}
+
+/// Keep the buffers of all `streams` filled. This runs forever and should be
+/// called from its own thread.
+pub fn run(streams: Streams, interval: Duration) {
+    loop {
+        std::thread::sleep(interval);
+        let mut streams = match streams.lock() {
+            Ok(streams) => streams,
+            Err(e) => {
+                error!("Audio clip streams are poisoned: {}", e);
+                return;
+            }
+        };
+        for (id, stream) in streams.iter_mut().filter(|(_, s)| !s.failed) {
+            if let Err(e) = stream.fill() {
+                error!("Failed to read audio for clip {}: {}", id, e);
+                stream.failed = true;
+            }
+        }
+    }
+}